@@ -223,6 +223,11 @@ impl BoundingBox {
     }
 
     pub fn intersect(&self, pos: Vector3<f32>, dir: Vector3<f32>) -> Option<f32> {
+        self.range(pos, dir).map(|(t_in, _)| t_in)
+    }
+
+    /* where the line through pos along dir enters and leaves the box */
+    pub fn range(&self, pos: Vector3<f32>, dir: Vector3<f32>) -> Option<(f32, f32)> {
         let mut in_x = f32::NEG_INFINITY;
         let mut out_x = f32::INFINITY;
         if dir.x == 0.0 {
@@ -274,7 +279,7 @@ impl BoundingBox {
         let t_in = in_x.max(in_y).max(in_z);
         let t_out = out_x.min(out_y).min(out_z);
         if t_in <= t_out {
-            Some(t_in)
+            Some((t_in, t_out))
         } else {
             None
         }
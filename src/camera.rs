@@ -0,0 +1,362 @@
+extern crate cgmath;
+
+use sampler::{concentric_disk_sample, Distribution2D};
+use scene::Differential;
+
+use std::f32::consts;
+use self::cgmath::{Vector3, InnerSpace};
+
+pub struct Camera {
+    pub pos: Vector3<f32>,
+    pub dir: Vector3<f32>,
+    pub up: Vector3<f32>,
+    pub fov: f32,
+    pub img_width: u32,
+    pub img_height: u32,
+    pub focaldist: f32,
+    /* radius of the aperture */
+    pub dof: f32,
+    pub aperture: Aperture,
+    pub projection: Projection,
+    /* what radiance in nits is scaled by to get pixel values, 1 leaves it alone */
+    pub exposure: f32,
+}
+
+/* the shape of the opening light passes through, which is also the shape out of focus
+ * highlights take */
+#[derive(Debug, Clone)]
+pub enum Aperture {
+    Disk,
+    /* a regular polygon with its first corner rotation degrees counterclockwise from straight up */
+    Polygon { blades: u32, rotation: f32 },
+    /* any shape, painted in a grayscale image covering the square around the aperture circle */
+    Mask(Distribution2D),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Projection {
+    Perspective,
+    /* parallel rays through a view height in scene units */
+    Orthographic { size: f32 },
+    /* equidistant fisheye, fov is the angle across the image circle inscribed in the frame */
+    Fisheye,
+    /* the full sphere of directions, longitude across and latitude down the image */
+    Equirectangular,
+    /* six 90 degree faces in a 3x2 grid: right, left, up on top and down, front, back below */
+    Cubemap,
+    /* rays traced from the film out through the elements of a real lens */
+    Realistic(LensSystem),
+}
+
+/* one spherical surface of a lens, or the aperture stop when radius is 0. lengths are in
+ * meters, thickness is the distance to the next surface towards the film and ior that of the
+ * glass (or air) between the two. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensElement {
+    pub radius: f32,
+    pub thickness: f32,
+    pub ior: f32,
+    pub aperture_radius: f32,
+}
+
+/* elements listed from the front of the lens to the back. the film sits thickness of the last
+ * element behind it, on the z axis with the lens towards -z. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LensSystem {
+    pub elements: Vec<LensElement>,
+    pub film_width: f32,
+    pub film_height: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Camera {
+        Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            up: Vector3::new(0.0, 1.0, 0.0),
+            fov: 40.0,
+            img_width: 800,
+            img_height: 600,
+            focaldist: 1.0,
+            dof: 0.0,
+            aperture: Aperture::Disk,
+            projection: Projection::Perspective,
+            exposure: 1.0,
+        }
+    }
+}
+
+/* saturation based exposure, ISO 12232: a pixel is white at 78 / (0.65 * shutter * iso / fstop^2)
+ * nits, 0.65 being how much light makes it through a typical lens */
+pub fn exposure(shutter: f32, iso: f32, fstop: f32) -> f32 {
+    0.65 * shutter * iso / (78.0 * fstop * fstop)
+}
+
+impl Projection {
+    pub fn from_name(name: &str) -> Option<Projection> {
+        match name {
+            "perspective" => Some(Projection::Perspective),
+            "orthographic" => Some(Projection::Orthographic { size: 0.0 }),
+            "fisheye" => Some(Projection::Fisheye),
+            "equirectangular" | "panorama" => Some(Projection::Equirectangular),
+            "cubemap" => Some(Projection::Cubemap),
+            "realistic" => Some(Projection::Realistic(LensSystem { elements: Vec::new(), film_width: 0.0, film_height: 0.0 })),
+            _ => None,
+        }
+    }
+}
+
+impl Camera {
+    /* how the ray from eye along dir through (x, y) changes from one pixel to the next, from the
+     * rays through the pixels to the right and below with the same lens sample. None where
+     * those aren't covered */
+    pub fn generate_differential(&self, x: f32, y: f32, lens: (f32, f32), eye: Vector3<f32>, dir: Vector3<f32>) -> Option<Differential> {
+        let (eye_x, dir_x) = self.generate_ray(x + 1.0, y, lens)?;
+        let (eye_y, dir_y) = self.generate_ray(x, y + 1.0, lens)?;
+        Some(Differential { dpdx: eye_x - eye, dpdy: eye_y - eye, dddx: dir_x - dir, dddy: dir_y - dir })
+    }
+
+    /* the ray through film position (x, y), in pixels from the top-left corner. lens is a point
+     * in [0, 1)^2 used for depth of field. returns None for film positions the projection
+     * doesn't cover, like the corners outside a fisheye's image circle. */
+    pub fn generate_ray(&self, x: f32, y: f32, lens: (f32, f32)) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let right = self.dir.cross(self.up);
+        let width = self.img_width as f32;
+        let height = self.img_height as f32;
+
+        match self.projection {
+            Projection::Realistic(ref system) => {
+                /* the lens flips the image, so the right of the frame is on the left of the film */
+                let film = Vector3::new((0.5 - x / width) * system.film_width, (y / height - 0.5) * system.film_height, 0.0);
+
+                /* aim at a point on the back element, rays that don't make it out are black */
+                let rear = system.elements.last().expect("lens has no elements");
+                let (u, v) = concentric_disk_sample(lens);
+                let target = Vector3::new(u * rear.aperture_radius, v * rear.aperture_radius, -rear.thickness);
+
+                let (o, d) = system.trace_from_film(film, (target - film).normalize())?;
+
+                /* lens space has x right, y up and the scene towards -z */
+                let eye = self.pos + o.x * right + o.y * self.up - o.z * self.dir;
+                let dir = d.x * right + d.y * self.up - d.z * self.dir;
+                Some((eye, dir.normalize()))
+            },
+            Projection::Perspective => {
+                let screen_height = ((self.fov / 2.0) * (2.0 * consts::PI / 360.0)).tan() * 2.0 * self.focaldist;
+                let screen_width = screen_height * width / height;
+
+                /* point on the screen, which sits at the focal distance */
+                let p = self.pos + self.focaldist * self.dir
+                    + (x / width - 0.5) * screen_width * right
+                    + (0.5 - y / height) * screen_height * self.up;
+
+                let (eye_x_offset, eye_y_offset) = self.aperture.sample(lens);
+                let eye = self.pos + eye_x_offset * self.dof * right + eye_y_offset * self.dof * self.up;
+
+                Some((eye, (p - eye).normalize()))
+            },
+            Projection::Orthographic { size } => {
+                let view_height = if size > 0.0 {
+                    size
+                } else {
+                    /* frame the focal plane the same way the perspective camera would */
+                    ((self.fov / 2.0) * (2.0 * consts::PI / 360.0)).tan() * 2.0 * self.focaldist
+                };
+                let view_width = view_height * width / height;
+
+                let eye = self.pos + (x / width - 0.5) * view_width * right + (0.5 - y / height) * view_height * self.up;
+                Some((eye, self.dir))
+            },
+            Projection::Fisheye => {
+                let radius = 0.5 * width.min(height);
+                let dx = (x - 0.5 * width) / radius;
+                let dy = (0.5 * height - y) / radius;
+                let r = (dx * dx + dy * dy).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+
+                let theta = r * 0.5 * self.fov * consts::PI / 180.0;
+                let phi = dy.atan2(dx);
+                let dir = theta.cos() * self.dir + theta.sin() * (phi.cos() * right + phi.sin() * self.up);
+                Some((self.pos, dir.normalize()))
+            },
+            Projection::Equirectangular => {
+                let phi = (x / width - 0.5) * 2.0 * consts::PI;
+                let latitude = (0.5 - y / height) * consts::PI;
+                let dir = latitude.cos() * (phi.cos() * self.dir + phi.sin() * right) + latitude.sin() * self.up;
+                Some((self.pos, dir.normalize()))
+            },
+            Projection::Cubemap => {
+                let face_width = width / 3.0;
+                let face_height = height / 2.0;
+                let column = ((x / face_width) as usize).min(2);
+                let row = ((y / face_height) as usize).min(1);
+                let s = 2.0 * (x - column as f32 * face_width) / face_width - 1.0;
+                let t = 1.0 - 2.0 * (y - row as f32 * face_height) / face_height;
+
+                /* forward, right and up of each face */
+                let (forward, face_right, face_up) = match (row, column) {
+                    (0, 0) => (right, -self.dir, self.up),
+                    (0, 1) => (-right, self.dir, self.up),
+                    (0, _) => (self.up, right, -self.dir),
+                    (_, 0) => (-self.up, right, self.dir),
+                    (_, 1) => (self.dir, right, self.up),
+                    (_, _) => (-self.dir, -right, self.up),
+                };
+
+                Some((self.pos, (forward + s * face_right + t * face_up).normalize()))
+            },
+        }
+    }
+}
+
+impl Aperture {
+    /* a point on the aperture, in a circle of radius 1 */
+    pub fn sample(&self, sample: (f32, f32)) -> (f32, f32) {
+        match *self {
+            Aperture::Disk => concentric_disk_sample(sample),
+            Aperture::Polygon { blades, rotation } => {
+                /* pick one of the triangles between the center and two neighbouring corners,
+                 * then a uniform point in it */
+                let blades = blades.max(3);
+                let scaled = sample.0 * blades as f32;
+                let blade = (scaled as u32).min(blades - 1);
+                let u = scaled - blade as f32;
+                let v = sample.1;
+
+                let angle = |i: u32| consts::PI / 2.0 + rotation * consts::PI / 180.0 + i as f32 * 2.0 * consts::PI / blades as f32;
+                let (a, b) = (angle(blade), angle(blade + 1));
+
+                let su = u.sqrt();
+                let (wa, wb) = (su * (1.0 - v), su * v);
+                (wa * a.cos() + wb * b.cos(), wa * a.sin() + wb * b.sin())
+            },
+            Aperture::Mask(ref mask) => {
+                let ((x, y), _) = mask.sample(sample);
+                (2.0 * x - 1.0, 1.0 - 2.0 * y)
+            },
+        }
+    }
+}
+
+impl LensSystem {
+    /* z of the front surface, with the film at 0 */
+    fn front_z(&self) -> f32 {
+        -self.elements.iter().map(|element| element.thickness).sum::<f32>()
+    }
+
+    /* follows a ray from the film out the front of the lens, None if it's blocked on the way */
+    pub fn trace_from_film(&self, o: Vector3<f32>, d: Vector3<f32>) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let (mut o, mut d) = (o, d);
+        let mut z = 0.0;
+        for i in (0..self.elements.len()).rev() {
+            let element = self.elements[i];
+            z -= element.thickness;
+
+            let ior_outside = if i > 0 { self.elements[i - 1].ior } else { 1.0 };
+            let (hit, refracted) = element.trace(o, d, z, element.ior, ior_outside)?;
+            o = hit;
+            d = refracted;
+        }
+        Some((o, d))
+    }
+
+    /* follows a ray from the scene in through the lens to the film side */
+    fn trace_from_scene(&self, o: Vector3<f32>, d: Vector3<f32>) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let (mut o, mut d) = (o, d);
+        let mut z = self.front_z();
+        for i in 0..self.elements.len() {
+            let element = self.elements[i];
+
+            let ior_outside = if i > 0 { self.elements[i - 1].ior } else { 1.0 };
+            let (hit, refracted) = element.trace(o, d, z, ior_outside, element.ior)?;
+            o = hit;
+            d = refracted;
+            z += element.thickness;
+        }
+        Some((o, d))
+    }
+
+    /* moves the film so that things focus_distance in front of it are sharp, treating the lens
+     * as a thick lens with principal planes and focal length found by tracing paraxial rays */
+    pub fn focus(&mut self, focus_distance: f32) {
+        let height = 0.001 * self.film_height;
+
+        /* a ray coming in parallel to the axis from the scene crosses it at the back focal point */
+        let (o, d) = self.trace_from_scene(Vector3::new(height, 0.0, self.front_z() - 1.0), Vector3::new(0.0, 0.0, 1.0))
+            .expect("paraxial ray could not pass through the lens");
+        let focal_point = o.z - o.x / d.x * d.z;
+        let back_principal_plane = o.z + (height - o.x) / d.x * d.z;
+        let focal_length = focal_point - back_principal_plane;
+
+        /* and one going out parallel from the film finds the front principal plane */
+        let (o, d) = self.trace_from_film(Vector3::new(height, 0.0, 1.0), Vector3::new(0.0, 0.0, -1.0))
+            .expect("paraxial ray could not pass through the lens");
+        let front_principal_plane = o.z + (height - o.x) / d.x * d.z;
+
+        if focal_length.is_nan() || focal_length <= 0.0 {
+            panic!("lens does not focus light");
+        }
+
+        /* with the film moved back by shift, solve 1/object + 1/image = 1/focal_length where
+         * object = front_principal_plane - shift + focus_distance and image = shift - back_principal_plane */
+        let a = front_principal_plane + focus_distance - back_principal_plane;
+        if a < 4.0 * focal_length {
+            panic!("lens can't focus that close");
+        }
+        let shift = 0.5 * (front_principal_plane + focus_distance + back_principal_plane - (a * (a - 4.0 * focal_length)).sqrt());
+
+        self.elements.last_mut().unwrap().thickness += shift;
+    }
+}
+
+impl LensElement {
+    /* refracts a ray at this surface, which crosses the axis at z, going from a medium with
+     * ior_in into one with ior_out */
+    fn trace(&self, o: Vector3<f32>, d: Vector3<f32>, z: f32, ior_in: f32, ior_out: f32) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        if self.radius == 0.0 {
+            let t = (z - o.z) / d.z;
+            let hit = o + t * d;
+            if t.is_nan() || t <= 0.0 || hit.x * hit.x + hit.y * hit.y > self.aperture_radius * self.aperture_radius {
+                return None;
+            }
+            return Some((hit, d));
+        }
+
+        let center = Vector3::new(0.0, 0.0, z + self.radius);
+        let oc = o - center;
+        let b = oc.dot(d);
+        let c = oc.dot(oc) - self.radius * self.radius;
+        let discriminant = b * b - c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        /* of the two intersections with the sphere, the one on the side the surface is on */
+        let root = discriminant.sqrt();
+        let closer = (d.z > 0.0) != (self.radius < 0.0);
+        let t = if closer { -b - root } else { -b + root };
+        if t < 0.0 {
+            return None;
+        }
+
+        let hit = o + t * d;
+        if hit.x * hit.x + hit.y * hit.y > self.aperture_radius * self.aperture_radius {
+            return None;
+        }
+
+        let mut normal = (hit - center).normalize();
+        if normal.dot(d) > 0.0 {
+            normal = -normal;
+        }
+        let eta = ior_in / ior_out;
+        let cos_i = -normal.dot(d);
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+        if sin2_t >= 1.0 {
+            return None;
+        }
+        let cos_t = (1.0 - sin2_t).sqrt();
+        Some((hit, (eta * d + (eta * cos_i - cos_t) * normal).normalize()))
+    }
+}
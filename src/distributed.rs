@@ -0,0 +1,388 @@
+use scene::*;
+use film::*;
+use render::*;
+use sampler::*;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rayon;
+
+/* every connection starts with the coordinator sending the magic and its sampling settings and
+ * the worker answering with the magic, how many tiles it can render at once and the image size
+ * it loaded. after that the coordinator sends a tile rect plus the pixels it has for that tile,
+ * and the worker sends back the same pixels with samples added, until the connection closes. */
+const PROTOCOL_MAGIC: &[u8; 8] = b"PTDIST02";
+
+const PIXEL_BYTES: usize = 20;
+
+/* tiles waiting for a worker, with the pixels rendered into them so far */
+type TileQueue = VecDeque<(Rect, Vec<Pixel>)>;
+
+trait Stream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/* addresses are host:port for tcp or unix:/path/to/socket */
+fn connect(address: &str) -> io::Result<Box<dyn Stream>> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        Ok(Box::new(UnixStream::connect(path)?))
+    } else {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl Listener {
+    fn bind(address: &str) -> io::Result<Listener> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            /* a socket file left over from an earlier run would make bind fail */
+            fs::remove_file(path).ok();
+            Ok(Listener::Unix(UnixListener::bind(path)?))
+        } else {
+            Ok(Listener::Tcp(TcpListener::bind(address)?))
+        }
+    }
+
+    fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            },
+            Listener::Unix(ref listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok(Box::new(stream))
+            },
+        }
+    }
+}
+
+/* runs a worker: renders tiles for whichever coordinators connect, one thread per connection */
+pub fn serve<F>(address: &str, width: u32, height: u32, sample_pixel: &F)
+    where F: Fn(u32, u32, &mut dyn Sampler) -> Color + Sync
+{
+    let listener = Listener::bind(address).expect("could not listen on worker address");
+    println!("worker listening on {}", address);
+
+    thread::scope(|scope| {
+        loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    /* errors like running out of file descriptors don't go away by retrying
+                     * right away */
+                    eprintln!("could not accept connection: {}", err);
+                    thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+
+            scope.spawn(move || {
+                if let Err(err) = serve_connection(stream, width, height, sample_pixel) {
+                    eprintln!("connection to coordinator lost: {}", err);
+                }
+            });
+        }
+    });
+}
+
+fn serve_connection<F>(mut stream: Box<dyn Stream>, width: u32, height: u32, sample_pixel: &F) -> io::Result<()>
+    where F: Fn(u32, u32, &mut dyn Sampler) -> Color
+{
+    read_magic(&mut stream)?;
+    let sampling = read_sampling(&mut stream)?;
+
+    let mut reply = Vec::new();
+    reply.extend_from_slice(PROTOCOL_MAGIC);
+    write_u32(&mut reply, rayon::current_num_threads() as u32)?;
+    write_u32(&mut reply, width)?;
+    write_u32(&mut reply, height)?;
+    stream.write_all(&reply)?;
+
+    loop {
+        let tile = match read_rect(&mut stream) {
+            Ok(tile) => tile,
+            /* the coordinator hangs up once it's out of tiles */
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let inside = |start: u32, size: u32, end: u32| start.checked_add(size).is_some_and(|last| last <= end);
+        if !inside(tile.x, tile.width, width) || !inside(tile.y, tile.height, height) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "tile outside of the image"));
+        }
+        let count = (tile.width as usize).checked_mul(tile.height as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "tile too large"))?;
+
+        let mut pixels = read_pixels(&mut stream, count)?;
+        render_tile(tile, &mut pixels, &sampling, sample_pixel);
+        write_pixels(&mut stream, &pixels)?;
+    }
+}
+
+/* renders region of film on remote workers. a tile whose worker fails, or doesn't answer within
+ * timeout, is put back in the queue for the others to pick up. returns an error if every worker
+ * is gone with tiles left over. */
+pub fn render_distributed<G>(film: &mut Film, region: Rect, sampling: &Sampling, workers: &[String],
+                             timeout: Option<Duration>, mut after_tile: G) -> Result<(), String>
+    where G: FnMut(&Film)
+{
+    let tiles = tiles(region, sampling.tile_size, sampling.tile_order);
+    let num_tiles = tiles.len();
+    let queue: TileQueue = tiles.into_iter().map(|tile| (tile, film.read_tile(tile))).collect();
+    let queue = Arc::new(Mutex::new(queue));
+    let remaining = Arc::new(AtomicUsize::new(num_tiles));
+
+    let (sender, receiver) = mpsc::channel();
+
+    let mut connections = 0;
+    for address in workers {
+        /* the first connection tells us how many tiles the worker can take at once */
+        let threads = match connect_worker(address, sampling, timeout, film) {
+            Ok((stream, threads)) => {
+                spawn_connection(address.clone(), stream, queue.clone(), remaining.clone(), sender.clone());
+                connections += 1;
+                threads
+            },
+            Err(err) => {
+                eprintln!("could not connect to worker {}: {}", address, err);
+                continue;
+            }
+        };
+
+        for _ in 1..threads {
+            match connect_worker(address, sampling, timeout, film) {
+                Ok((stream, _)) => {
+                    spawn_connection(address.clone(), stream, queue.clone(), remaining.clone(), sender.clone());
+                    connections += 1;
+                },
+                Err(err) => {
+                    eprintln!("could not connect to worker {}: {}", address, err);
+                    break;
+                }
+            }
+        }
+    }
+    drop(sender);
+
+    if connections == 0 {
+        return Err("could not connect to any worker".to_string());
+    }
+
+    let mut progress = Progress::new(num_tiles, (region.width * region.height) as usize);
+    progress.print();
+
+    let mut done = 0;
+    while done < num_tiles && !interrupted() {
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok((tile, pixels)) => {
+                film.write_tile(tile, &pixels);
+                done += 1;
+                remaining.fetch_sub(1, Ordering::SeqCst);
+                progress.finish_tile(tile);
+                progress.print();
+                after_tile(film);
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                eprintln!();
+                return Err(format!("all workers failed with {} tiles left", num_tiles - done));
+            },
+        }
+    }
+
+    eprintln!();
+    Ok(())
+}
+
+fn connect_worker(address: &str, sampling: &Sampling, timeout: Option<Duration>, film: &Film) -> io::Result<(Box<dyn Stream>, u32)> {
+    let mut stream = connect(address)?;
+    /* a worker that hangs without closing the connection fails like one that went away */
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    let mut request = Vec::new();
+    request.extend_from_slice(PROTOCOL_MAGIC);
+    write_sampling(&mut request, sampling)?;
+    stream.write_all(&request)?;
+
+    read_magic(&mut stream)?;
+    let threads = read_u32(&mut stream)?;
+    let width = read_u32(&mut stream)?;
+    let height = read_u32(&mut stream)?;
+    if width != film.width || height != film.height {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("worker loaded a {}x{} image, expected {}x{}", width, height, film.width, film.height)));
+    }
+
+    Ok((stream, threads.max(1)))
+}
+
+fn spawn_connection(address: String, mut stream: Box<dyn Stream>, queue: Arc<Mutex<TileQueue>>,
+                    remaining: Arc<AtomicUsize>, sender: mpsc::Sender<(Rect, Vec<Pixel>)>) {
+    thread::spawn(move || {
+        loop {
+            if interrupted() {
+                return;
+            }
+
+            let job = queue.lock().unwrap().pop_front();
+            let (tile, pixels) = match job {
+                Some(job) => job,
+                None => {
+                    /* tiles still out on other workers may come back if those workers die */
+                    if remaining.load(Ordering::SeqCst) == 0 {
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+
+            match render_remote(&mut stream, tile, &pixels) {
+                Ok(rendered) => {
+                    if sender.send((tile, rendered)).is_err() {
+                        return;
+                    }
+                },
+                Err(err) => {
+                    eprintln!("\nworker {} failed, retrying its tile elsewhere: {}", address, err);
+                    queue.lock().unwrap().push_front((tile, pixels));
+                    return;
+                }
+            }
+        }
+    });
+}
+
+fn render_remote(stream: &mut Box<dyn Stream>, tile: Rect, pixels: &[Pixel]) -> io::Result<Vec<Pixel>> {
+    let mut request = Vec::with_capacity(16 + pixels.len() * PIXEL_BYTES);
+    write_rect(&mut request, tile)?;
+    for pixel in pixels {
+        write_pixel(&mut request, pixel)?;
+    }
+    stream.write_all(&request)?;
+
+    read_pixels(stream, pixels.len())
+}
+
+fn read_magic<R: Read>(r: &mut R) -> io::Result<()> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != PROTOCOL_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a path tracer worker connection"));
+    }
+    Ok(())
+}
+
+fn write_sampling<W: Write>(w: &mut W, sampling: &Sampling) -> io::Result<()> {
+    let sampler = match sampling.sampler {
+        SamplerType::Random => 0,
+        SamplerType::Sobol => 1,
+        SamplerType::Pmj02 => 2,
+    };
+
+    write_u32(w, sampling.initial_samples)?;
+    write_u32(w, sampling.max_samples)?;
+    write_u32(w, sampling.threshold.to_bits())?;
+    write_u32(w, sampler)?;
+    write_u32(w, sampling.seed)
+}
+
+fn read_sampling<R: Read>(r: &mut R) -> io::Result<Sampling> {
+    let initial_samples = read_u32(r)?;
+    let max_samples = read_u32(r)?;
+    if initial_samples == 0 || initial_samples > max_samples {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid sample counts"));
+    }
+    let threshold = f32::from_bits(read_u32(r)?);
+    let sampler = match read_u32(r)? {
+        0 => SamplerType::Random,
+        1 => SamplerType::Sobol,
+        2 => SamplerType::Pmj02,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown sampler type")),
+    };
+    let seed = read_u32(r)?;
+    Ok(Sampling {
+        initial_samples,
+        max_samples,
+        threshold,
+        sampler,
+        seed,
+        ..Default::default()
+    })
+}
+
+fn write_rect<W: Write>(w: &mut W, rect: Rect) -> io::Result<()> {
+    write_u32(w, rect.x)?;
+    write_u32(w, rect.y)?;
+    write_u32(w, rect.width)?;
+    write_u32(w, rect.height)
+}
+
+fn read_rect<R: Read>(r: &mut R) -> io::Result<Rect> {
+    Ok(Rect {
+        x: read_u32(r)?,
+        y: read_u32(r)?,
+        width: read_u32(r)?,
+        height: read_u32(r)?,
+    })
+}
+
+fn write_pixels<W: Write>(w: &mut W, pixels: &[Pixel]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(pixels.len() * PIXEL_BYTES);
+    for pixel in pixels {
+        write_pixel(&mut buf, pixel)?;
+    }
+    w.write_all(&buf)
+}
+
+/* reads the whole tile in one go rather than a few bytes at a time off the socket */
+fn read_pixels<R: Read>(r: &mut R, count: usize) -> io::Result<Vec<Pixel>> {
+    let mut buf = vec![0; count * PIXEL_BYTES];
+    r.read_exact(&mut buf)?;
+
+    let mut slice = &buf[..];
+    let mut pixels = Vec::with_capacity(count);
+    for _ in 0..count {
+        pixels.push(read_pixel(&mut slice)?);
+    }
+    Ok(pixels)
+}
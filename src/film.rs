@@ -0,0 +1,202 @@
+extern crate cgmath;
+
+use scene::*;
+use render::Rect;
+
+use std::f32;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use self::cgmath::Vector3;
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"PTCKPT03";
+
+/* keeps the relative error of near-black pixels from blowing up */
+const MIN_LUMINANCE: f32 = 0.01;
+
+/* running statistics for the samples taken so far in one pixel */
+#[derive(Debug, Clone, Copy)]
+pub struct Pixel {
+    pub sum: Color,
+    /* the luminance of each sample squared, for its variance */
+    pub sum_lum_sqr: f32,
+    pub samples: u32,
+}
+
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Pixel>,
+}
+
+impl Default for Pixel {
+    fn default() -> Pixel {
+        Pixel {
+            sum: Vector3::new(0.0, 0.0, 0.0),
+            sum_lum_sqr: 0.0,
+            samples: 0,
+        }
+    }
+}
+
+impl Pixel {
+    pub fn add_sample(&mut self, sample: Color) {
+        self.sum += sample;
+        self.sum_lum_sqr += luminance(sample) * luminance(sample);
+        self.samples += 1;
+    }
+
+    /* standard error of the mean luminance, relative to that mean */
+    pub fn relative_error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+
+        let n = self.samples as f32;
+        let mean = luminance(self.sum) / n;
+        let variance = ((self.sum_lum_sqr - n * mean * mean) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt() / (mean + MIN_LUMINANCE)
+    }
+
+    pub fn color(&self) -> Color {
+        if self.samples == 0 {
+            Vector3::new(0.0, 0.0, 0.0)
+        } else {
+            self.sum / self.samples as f32
+        }
+    }
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![Pixel::default(); (width * height) as usize],
+        }
+    }
+
+    pub fn read_tile(&self, tile: Rect) -> Vec<Pixel> {
+        let mut pixels = Vec::with_capacity((tile.width * tile.height) as usize);
+        for y in tile.y..tile.y + tile.height {
+            let start = (y * self.width + tile.x) as usize;
+            pixels.extend_from_slice(&self.pixels[start..start + tile.width as usize]);
+        }
+        pixels
+    }
+
+    pub fn write_tile(&mut self, tile: Rect, pixels: &[Pixel]) {
+        for (row, y) in (tile.y..tile.y + tile.height).enumerate() {
+            let start = (y * self.width + tile.x) as usize;
+            let tile_start = row * tile.width as usize;
+            self.pixels[start..start + tile.width as usize].copy_from_slice(&pixels[tile_start..tile_start + tile.width as usize]);
+        }
+    }
+
+    pub fn crop(&self, rect: Rect) -> Film {
+        Film {
+            width: rect.width,
+            height: rect.height,
+            pixels: self.read_tile(rect),
+        }
+    }
+
+    /* a full-size film holding only the pixels inside rect, black everywhere else */
+    pub fn mask(&self, rect: Rect) -> Film {
+        let mut film = Film::new(self.width, self.height);
+        film.write_tile(rect, &self.read_tile(rect));
+        film
+    }
+
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let mut img = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            img.extend_from_slice(&color_as_u8_array(pixel.color()));
+        }
+        img
+    }
+
+    /* sample count per pixel as greyscale, white being max_samples */
+    pub fn heatmap_rgba8(&self, max_samples: u32) -> Vec<u8> {
+        let mut img = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            let brightness = (255.0 * pixel.samples as f32 / max_samples as f32).min(255.0) as u8;
+            img.extend_from_slice(&[brightness, brightness, brightness, 255]);
+        }
+        img
+    }
+
+    /* writes to a temporary file first so an interrupted write never clobbers the previous checkpoint */
+    pub fn save_checkpoint(&self, filename: &str) -> io::Result<()> {
+        let tmp_filename = format!("{}.tmp", filename);
+        {
+            let mut w = BufWriter::new(File::create(&tmp_filename)?);
+            w.write_all(CHECKPOINT_MAGIC)?;
+            write_u32(&mut w, self.width)?;
+            write_u32(&mut w, self.height)?;
+            for pixel in &self.pixels {
+                write_pixel(&mut w, pixel)?;
+            }
+            w.flush()?;
+        }
+        fs::rename(&tmp_filename, filename)
+    }
+
+    pub fn load_checkpoint(filename: &str) -> io::Result<Film> {
+        let mut r = BufReader::new(File::open(filename)?);
+
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a checkpoint file"));
+        }
+
+        let width = read_u32(&mut r)?;
+        let height = read_u32(&mut r)?;
+        let mut film = Film::new(width, height);
+        for pixel in film.pixels.iter_mut() {
+            *pixel = read_pixel(&mut r)?;
+        }
+
+        Ok(film)
+    }
+}
+
+pub fn write_pixel<W: Write>(w: &mut W, pixel: &Pixel) -> io::Result<()> {
+    write_color(w, pixel.sum)?;
+    write_u32(w, pixel.sum_lum_sqr.to_bits())?;
+    write_u32(w, pixel.samples)
+}
+
+pub fn read_pixel<R: Read>(r: &mut R) -> io::Result<Pixel> {
+    Ok(Pixel {
+        sum: read_color(r)?,
+        sum_lum_sqr: f32::from_bits(read_u32(r)?),
+        samples: read_u32(r)?,
+    })
+}
+
+pub fn write_u32<W: Write>(w: &mut W, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_color<W: Write>(w: &mut W, color: Color) -> io::Result<()> {
+    write_u32(w, color.x.to_bits())?;
+    write_u32(w, color.y.to_bits())?;
+    write_u32(w, color.z.to_bits())
+}
+
+pub fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_color<R: Read>(r: &mut R) -> io::Result<Color> {
+    let x = f32::from_bits(read_u32(r)?);
+    let y = f32::from_bits(read_u32(r)?);
+    let z = f32::from_bits(read_u32(r)?);
+    Ok(Vector3::new(x, y, z))
+}
@@ -0,0 +1,138 @@
+extern crate cgmath;
+
+use scene::*;
+use spectrum::Channels;
+
+use std::f32::consts;
+use std::ops::{Add, Sub, Mul, Div};
+use self::cgmath::Vector3;
+
+/* a metal, with the real and imaginary parts of its index of refraction for red, green and
+ * blue, under an optional coating */
+#[derive(Debug, Clone)]
+pub struct Conductor {
+    pub eta: Color,
+    pub k: Color,
+    pub film: Option<Film>,
+}
+
+/* a thin transparent layer, thickness in nanometers, whose reflections off its top and bottom
+ * interfere and color the light, like oil on water or anodized metal */
+#[derive(Debug, Clone)]
+pub struct Film {
+    pub thickness: f32,
+    pub index: f32,
+}
+
+#[derive(Clone, Copy)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Conductor {
+    /* a metal by name, with indices at 650, 550 and 450nm */
+    pub fn preset(name: &str) -> Option<Conductor> {
+        let (eta, k) = match name {
+            "gold" => (Vector3::new(0.143, 0.374, 1.442), Vector3::new(3.983, 2.386, 1.603)),
+            "silver" => (Vector3::new(0.155, 0.117, 0.138), Vector3::new(4.827, 3.122, 2.147)),
+            "copper" => (Vector3::new(0.200, 0.924, 1.102), Vector3::new(3.912, 2.452, 2.142)),
+            "aluminium" => (Vector3::new(1.657, 0.880, 0.521), Vector3::new(9.224, 6.270, 4.837)),
+            _ => return None,
+        };
+        Some(Conductor { eta, k, film: None })
+    }
+
+    /* the fraction of light reflected in each channel for an angle of incidence with cosine cos */
+    pub fn reflectance(&self, cos: f32, channels: Channels) -> Color {
+        let wavelengths = channels.wavelengths();
+        let eta = channels.property(self.eta);
+        let k = channels.property(self.k);
+        let reflectance = |wavelength: f32, eta: f32, k: f32| {
+            film_reflectance(cos, self.film.as_ref(), Complex { re: eta, im: k }, wavelength)
+        };
+        Vector3::new(
+            reflectance(wavelengths.x, eta.x, k.x),
+            reflectance(wavelengths.y, eta.y, k.y),
+            reflectance(wavelengths.z, eta.z, k.z),
+        )
+    }
+}
+
+/* unpolarized reflectance from air onto a substrate with a complex index, through a film if
+ * there is one: the Airy sum of the light bouncing back and forth inside the film, with the
+ * exact Fresnel amplitudes at both of its interfaces */
+fn film_reflectance(cos: f32, film: Option<&Film>, substrate: Complex, wavelength: f32) -> f32 {
+    let cos = cos.clamp(0.0, 1.0);
+    let sin_sqr = 1.0 - cos * cos;
+    let (film_index, thickness) = film.map_or((1.0, 0.0), |film| (film.index, film.thickness));
+
+    let n0 = Complex::real(1.0);
+    let n1 = Complex::real(film_index);
+    let n2 = substrate;
+    let cos0 = Complex::real(cos);
+    let cos1 = Complex::real((1.0 - sin_sqr / (film_index * film_index)).max(0.0).sqrt());
+    let cos2 = (Complex::real(1.0) - Complex::real(sin_sqr) / (n2 * n2)).sqrt();
+
+    /* the phase the light picks up going down through the film and back up */
+    let phase = 4.0 * consts::PI * film_index * thickness * cos1.re / wavelength;
+    let round_trip = Complex { re: phase.cos(), im: phase.sin() };
+
+    let airy = |r01: Complex, r12: Complex| {
+        let r = (r01 + r12 * round_trip) / (Complex::real(1.0) + r01 * r12 * round_trip);
+        r.norm_sqr()
+    };
+    let s = airy((n0 * cos0 - n1 * cos1) / (n0 * cos0 + n1 * cos1), (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2));
+    let p = airy((n1 * cos0 - n0 * cos1) / (n1 * cos0 + n0 * cos1), (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2));
+    (0.5 * (s + p)).min(1.0)
+}
+
+impl Complex {
+    fn real(re: f32) -> Complex {
+        Complex { re, im: 0.0 }
+    }
+
+    fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    /* the principal square root */
+    fn sqrt(self) -> Complex {
+        let norm = self.norm_sqr().sqrt();
+        let re = (0.5 * (norm + self.re)).max(0.0).sqrt();
+        let im = (0.5 * (norm - self.re)).max(0.0).sqrt();
+        Complex { re, im: if self.im < 0.0 { -im } else { im } }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex { re: self.re + other.re, im: self.im + other.im }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex { re: self.re - other.re, im: self.im - other.im }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex { re: self.re * other.re - self.im * other.im, im: self.re * other.im + self.im * other.re }
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, other: Complex) -> Complex {
+        let denominator = other.norm_sqr();
+        Complex {
+            re: (self.re * other.re + self.im * other.im) / denominator,
+            im: (self.im * other.re - self.re * other.im) / denominator,
+        }
+    }
+}
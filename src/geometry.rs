@@ -2,16 +2,23 @@ extern crate cgmath;
 
 use scene::*;
 use bvh::*;
+use grid::DensityGrid;
+use light::orthonormal_basis;
 
-use self::cgmath::{Vector3, InnerSpace};
+use self::cgmath::{Vector3, InnerSpace, ElementWise};
 
 use std::f32::consts;
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub enum Geometry {
     Sphere,
     Plane,
-    Mesh(Mesh),
+    /* the unit disk in the xy plane */
+    Disk,
+    Mesh(Box<Mesh>),
+    /* the box around a density grid, which a volume material fills */
+    Volume(Box<DensityGrid>),
 }
 
 #[derive(Debug)]
@@ -22,6 +29,10 @@ pub struct Mesh {
     pub normal_triangles: Vec<(usize, usize, usize)>,
     pub texture_vertices: Vec<Vector3<f32>>,
     pub texture_triangles: Vec<(usize, usize, usize)>,
+    /* the directions u and v of the texture coordinates run in, smoothed like the normals and
+     * indexed the same way */
+    pub tangents: Vec<Vector3<f32>>,
+    pub bitangents: Vec<Vector3<f32>>,
     pub bounding_box: BoundingBox,
     pub bvh: BVH,
 }
@@ -56,13 +67,23 @@ impl Geometry {
 
                         let hit_pos = pos + t * dir;
                         let normal = hit_pos.normalize();
+                        /* u goes around the z axis and v from pole to pole */
+                        let tangent = 2.0 * consts::PI * Vector3::new(-normal.y, normal.x, 0.0);
+                        let bitangent = if tangent.magnitude2() > 0.0 {
+                            consts::PI * normal.cross(tangent.normalize())
+                        } else {
+                            Vector3::new(0.0, 0.0, 0.0)
+                        };
 
                         Some(HitInfo {
                             z: t,
                             pos: hit_pos,
                             uv: Vector3::new(0.5 + (normal.y).atan2(normal.x) / (2.0 * consts::PI), 0.5 - (-normal.z).asin() / consts::PI, 0.0),
                             normal: normal,
+                            tangent,
+                            bitangent,
                             side: side,
+                            footprint: 0.0,
                         })
                     } else {
                         None
@@ -81,7 +102,32 @@ impl Geometry {
                             pos: p,
                             uv: Vector3::new(0.5 + 0.5 * p.x, 0.5 + 0.5 * p.y, 0.0),
                             normal: Vector3::new(0.0, 0.0, 1.0),
+                            tangent: Vector3::new(2.0, 0.0, 0.0),
+                            bitangent: Vector3::new(0.0, 2.0, 0.0),
+                            side: if pos.z > 0.0 { Side::Front } else { Side::Back },
+                            footprint: 0.0,
+                        })
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            },
+            Geometry::Disk => {
+                let t = -(pos.z / dir.z);
+                if t > 0.0 {
+                    let p = pos + t * dir;
+                    if p.x * p.x + p.y * p.y < 1.0 {
+                        Some(HitInfo {
+                            z: t,
+                            pos: p,
+                            uv: Vector3::new(0.5 + 0.5 * p.x, 0.5 + 0.5 * p.y, 0.0),
+                            normal: Vector3::new(0.0, 0.0, 1.0),
+                            tangent: Vector3::new(2.0, 0.0, 0.0),
+                            bitangent: Vector3::new(0.0, 2.0, 0.0),
                             side: if pos.z > 0.0 { Side::Front } else { Side::Back },
+                            footprint: 0.0,
                         })
                     } else {
                         None
@@ -92,6 +138,39 @@ impl Geometry {
             },
             Geometry::Mesh(ref mesh) => {
                 mesh.intersect(pos, dir)
+            },
+            Geometry::Volume(ref grid) => {
+                let bounds = grid.bounds;
+                bounds.range(pos, dir).and_then(|(t_in, t_out)| {
+                    let (t, side) = if t_in > 0.0 {
+                        (t_in, Side::Front)
+                    } else if t_out > 0.0 {
+                        (t_out, Side::Back)
+                    } else {
+                        return None;
+                    };
+
+                    /* the face the hit is nearest to */
+                    let p = pos + t * dir;
+                    let faces = [
+                        ((p.x - bounds.p1.x).abs(), -Vector3::unit_x()), ((p.x - bounds.p2.x).abs(), Vector3::unit_x()),
+                        ((p.y - bounds.p1.y).abs(), -Vector3::unit_y()), ((p.y - bounds.p2.y).abs(), Vector3::unit_y()),
+                        ((p.z - bounds.p1.z).abs(), -Vector3::unit_z()), ((p.z - bounds.p2.z).abs(), Vector3::unit_z()),
+                    ];
+                    let normal = faces.iter().fold(faces[0], |nearest, &face| if face.0 < nearest.0 { face } else { nearest }).1;
+                    let (tangent, bitangent) = orthonormal_basis(normal);
+
+                    Some(HitInfo {
+                        z: t,
+                        pos: p,
+                        uv: (p - bounds.p1).div_element_wise(bounds.p2 - bounds.p1),
+                        normal,
+                        tangent,
+                        bitangent,
+                        side,
+                        footprint: 0.0,
+                    })
+                })
             }
         }
     }
@@ -103,11 +182,14 @@ impl Bounded for Geometry {
             Geometry::Sphere => {
                 BoundingBox::new(-1.0, -1.0, -1.0, 1.0, 1.0, 1.0)
             },
-            Geometry::Plane => {
+            Geometry::Plane | Geometry::Disk => {
                 BoundingBox::new(-1.0, -1.0, 0.0, 1.0, 1.0, 0.0)
             },
             Geometry::Mesh(ref mesh) => {
                 mesh.bounding_box
+            },
+            Geometry::Volume(ref grid) => {
+                grid.bounds
             }
         }
     }
@@ -129,6 +211,126 @@ impl Mesh {
         (1.0 - u - v) * self.texture_vertices[points.0] + u * self.texture_vertices[points.1] + v * self.texture_vertices[points.2]
     }
 
+    fn get_tangents(&self, face: usize, u: f32, v: f32) -> (Vector3<f32>, Vector3<f32>) {
+        let points = self.normal_triangles[face];
+        ((1.0 - u - v) * self.tangents[points.0] + u * self.tangents[points.1] + v * self.tangents[points.2],
+         (1.0 - u - v) * self.bitangents[points.0] + u * self.bitangents[points.1] + v * self.bitangents[points.2])
+    }
+
+    /* how far the surface moves for a step in u and in v across each triangle, averaged over the
+     * triangles around each normal */
+    pub fn compute_tangents(&mut self) {
+        self.tangents = vec![Vector3::new(0.0, 0.0, 0.0); self.normals.len()];
+        self.bitangents = vec![Vector3::new(0.0, 0.0, 0.0); self.normals.len()];
+        let mut counts = vec![0; self.normals.len()];
+
+        for i in 0..self.triangles.len() {
+            let (a, b, c) = self.triangles[i];
+            let (ta, tb, tc) = self.texture_triangles[i];
+            let (e1, e2) = (self.vertices[b] - self.vertices[a], self.vertices[c] - self.vertices[a]);
+            let (d1, d2) = (self.texture_vertices[tb] - self.texture_vertices[ta], self.texture_vertices[tc] - self.texture_vertices[ta]);
+
+            let det = d1.x * d2.y - d2.x * d1.y;
+            if det.abs() < EPSILON {
+                continue;
+            }
+            let tangent = (e1 * d2.y - e2 * d1.y) / det;
+            let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+
+            let (na, nb, nc) = self.normal_triangles[i];
+            for &n in &[na, nb, nc] {
+                self.tangents[n] += tangent;
+                self.bitangents[n] += bitangent;
+                counts[n] += 1;
+            }
+        }
+
+        for (n, &count) in counts.iter().enumerate() {
+            if count > 0 {
+                self.tangents[n] /= count as f32;
+                self.bitangents[n] /= count as f32;
+            }
+        }
+    }
+
+    /* splits every triangle into four, subdivisions times over, with the new points halfway along
+     * the edges shared between neighbouring triangles */
+    pub fn subdivide(&mut self, subdivisions: u32) {
+        fn midpoint(points: &mut Vec<Vector3<f32>>, midpoints: &mut HashMap<(usize, usize), usize>, a: usize, b: usize) -> usize {
+            let edge = (a.min(b), a.max(b));
+            if let Some(&point) = midpoints.get(&edge) {
+                return point;
+            }
+            points.push(0.5 * (points[a] + points[b]));
+            midpoints.insert(edge, points.len() - 1);
+            points.len() - 1
+        }
+
+        fn split(points: &mut Vec<Vector3<f32>>, midpoints: &mut HashMap<(usize, usize), usize>, triangle: (usize, usize, usize), out: &mut Vec<(usize, usize, usize)>) {
+            let (a, b, c) = triangle;
+            let ab = midpoint(points, midpoints, a, b);
+            let bc = midpoint(points, midpoints, b, c);
+            let ca = midpoint(points, midpoints, c, a);
+            out.push((a, ab, ca));
+            out.push((ab, b, bc));
+            out.push((ca, bc, c));
+            out.push((ab, bc, ca));
+        }
+
+        for _ in 0..subdivisions {
+            let (mut vertex_midpoints, mut normal_midpoints, mut texture_midpoints) = (HashMap::new(), HashMap::new(), HashMap::new());
+            let (mut triangles, mut normal_triangles, mut texture_triangles) = (Vec::new(), Vec::new(), Vec::new());
+
+            for i in 0..self.triangles.len() {
+                split(&mut self.vertices, &mut vertex_midpoints, self.triangles[i], &mut triangles);
+                split(&mut self.normals, &mut normal_midpoints, self.normal_triangles[i], &mut normal_triangles);
+                split(&mut self.texture_vertices, &mut texture_midpoints, self.texture_triangles[i], &mut texture_triangles);
+            }
+
+            self.triangles = triangles;
+            self.normal_triangles = normal_triangles;
+            self.texture_triangles = texture_triangles;
+        }
+    }
+
+    /* moves every vertex along its normal by the height texture's value at its texture
+     * coordinates. normals are then worked out again from the moved surface */
+    pub fn displace(&mut self, height: &Texture) {
+        let mut displaced = vec![false; self.vertices.len()];
+        for i in 0..self.triangles.len() {
+            let (a, b, c) = self.triangles[i];
+            let (ta, tb, tc) = self.texture_triangles[i];
+            let (na, nb, nc) = self.normal_triangles[i];
+            for &(vertex, texture_vertex, normal) in &[(a, ta, na), (b, tb, nb), (c, tc, nc)] {
+                if displaced[vertex] {
+                    continue;
+                }
+                let texel = height.sample(self.texture_vertices[texture_vertex]);
+                let offset = (texel.x + texel.y + texel.z) / 3.0;
+                self.vertices[vertex] += offset * self.normals[normal].normalize();
+                displaced[vertex] = true;
+            }
+        }
+
+        let mut normals = vec![Vector3::new(0.0, 0.0, 0.0); self.vertices.len()];
+        for &(a, b, c) in &self.triangles {
+            let face_normal = (self.vertices[b] - self.vertices[a]).cross(self.vertices[c] - self.vertices[a]);
+            normals[a] += face_normal;
+            normals[b] += face_normal;
+            normals[c] += face_normal;
+        }
+        self.normals = normals.into_iter().map(|normal| if normal.magnitude2() > 0.0 { normal.normalize() } else { normal }).collect();
+        self.normal_triangles = self.triangles.clone();
+        self.compute_tangents();
+
+        let mut bounding_box = BoundingBox { p1: self.vertices[0], p2: self.vertices[0] };
+        for vertex in &self.vertices {
+            bounding_box.union(&BoundingBox { p1: *vertex, p2: *vertex });
+        }
+        self.bounding_box = bounding_box;
+        self.bvh = Mesh::build_bvh(&self.vertices, &self.triangles, bounding_box);
+    }
+
     fn intersect(&self, pos: Vector3<f32>, dir: Vector3<f32>) -> Option<HitInfo> {
         let mut nearest: Option<HitInfo> = None;
 
@@ -144,12 +346,16 @@ impl Mesh {
                         continue;
                     }
                 }
+                let (tangent, bitangent) = self.get_tangents(i, u, v);
                 nearest = Some(HitInfo {
                     z: t,
                     pos: self.get_point(i, u, v),
                     uv: self.get_texture_vertex(i, u, v),
                     normal: self.get_normal(i, u, v),
+                    tangent,
+                    bitangent,
                     side: side,
+                    footprint: 0.0,
                 })
             }
         }
@@ -0,0 +1,179 @@
+extern crate cgmath;
+
+use bvh::BoundingBox;
+
+use std::fs::File;
+use std::io::prelude::*;
+use self::cgmath::{Vector3, Matrix3, SquareMatrix, ElementWise};
+
+/* densities on a grid of voxels filling bounds, x fastest, then y, then z */
+#[derive(Debug)]
+pub struct DensityGrid {
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+    pub densities: Vec<f32>,
+    pub max_density: f32,
+    pub bounds: BoundingBox,
+    /* from world space into the space of the object holding the grid */
+    pub to_local: Matrix3<f32>,
+    pub to_local_translate: Vector3<f32>,
+}
+
+impl DensityGrid {
+    /* trilinearly interpolated density at a point in world space, 0 outside the grid's bounds */
+    pub fn density(&self, pos: Vector3<f32>) -> f32 {
+        let local = self.to_local * pos + self.to_local_translate;
+        let size = self.bounds.p2 - self.bounds.p1;
+        let p = (local - self.bounds.p1).div_element_wise(size);
+        if !(p.x >= 0.0 && p.x <= 1.0 && p.y >= 0.0 && p.y <= 1.0 && p.z >= 0.0 && p.z <= 1.0) {
+            return 0.0;
+        }
+
+        /* voxel values sit at voxel centers, and the outermost ones carry on to the edges */
+        let x = p.x * self.width as f32 - 0.5;
+        let y = p.y * self.height as f32 - 0.5;
+        let z = p.z * self.depth as f32 - 0.5;
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (xt, yt, zt) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as isize, y0 as isize, z0 as isize);
+        let voxel = |x: isize, y: isize, z: isize| {
+            let x = x.max(0).min(self.width as isize - 1) as usize;
+            let y = y.max(0).min(self.height as isize - 1) as usize;
+            let z = z.max(0).min(self.depth as isize - 1) as usize;
+            self.densities[(z * self.height + y) * self.width + x]
+        };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        let plane = |z: isize| {
+            lerp(lerp(voxel(x0, y0, z), voxel(x0 + 1, y0, z), xt),
+                 lerp(voxel(x0, y0 + 1, z), voxel(x0 + 1, y0 + 1, z), xt), yt)
+        };
+        lerp(plane(z0), plane(z0 + 1), zt)
+    }
+
+    /* where the object holding the grid sits in the world */
+    pub fn place(&mut self, transform: Matrix3<f32>, translate: Vector3<f32>) {
+        self.to_local = transform.invert().expect("volume transform can't be inverted");
+        self.to_local_translate = -(self.to_local * translate);
+    }
+}
+
+/* mitsuba's .vol format: "VOL" and version 3, then the encoding (1 for 32 bit floats, 3 for
+ * bytes), the resolution in x, y and z, the number of channels and the bounding box, all little
+ * endian, followed by the voxels. only the first channel is used */
+pub fn load_density_grid(filename: &str) -> DensityGrid {
+    let mut f = File::open(filename).expect("volume file not found");
+    let mut data = Vec::new();
+    f.read_to_end(&mut data).expect("could not read volume file");
+
+    load_vol(&data).unwrap_or_else(|err| panic!("could not read {}: {}", filename, err))
+}
+
+fn load_vol(data: &[u8]) -> Result<DensityGrid, String> {
+    if data.len() < 48 || &data[0..3] != b"VOL" {
+        return Err("not a .vol file".to_string());
+    }
+    if data[3] != 3 {
+        return Err(format!("version {} isn't supported", data[3]));
+    }
+
+    let read_u32 = |pos: usize| data[pos] as u32 | (data[pos + 1] as u32) << 8 | (data[pos + 2] as u32) << 16 | (data[pos + 3] as u32) << 24;
+    let read_f32 = |pos: usize| f32::from_bits(read_u32(pos));
+
+    let encoding = read_u32(4);
+    let width = read_u32(8) as usize;
+    let height = read_u32(12) as usize;
+    let depth = read_u32(16) as usize;
+    let channels = read_u32(20) as usize;
+    let bounds = BoundingBox::new(read_f32(24), read_f32(28), read_f32(32), read_f32(36), read_f32(40), read_f32(44));
+    if width == 0 || height == 0 || depth == 0 || channels == 0 {
+        return Err("empty grid".to_string());
+    }
+    if !(bounds.p1.x < bounds.p2.x && bounds.p1.y < bounds.p2.y && bounds.p1.z < bounds.p2.z) {
+        return Err("empty bounding box".to_string());
+    }
+
+    let bytes_per_value = match encoding {
+        1 => 4,
+        3 => 1,
+        _ => return Err(format!("encoding {} isn't supported", encoding)),
+    };
+    /* sizes made up by a broken header can overflow before they're compared to the file */
+    let count = width.checked_mul(height).and_then(|count| count.checked_mul(depth));
+    let size = count.and_then(|count| count.checked_mul(channels * bytes_per_value)).and_then(|size| size.checked_add(48));
+    let count = match (count, size) {
+        (Some(count), Some(size)) if size <= data.len() => count,
+        _ => return Err("voxel data ended early".to_string()),
+    };
+
+    let mut densities = Vec::with_capacity(count);
+    for i in 0..count {
+        let pos = 48 + i * channels * bytes_per_value;
+        densities.push(match encoding {
+            1 => read_f32(pos),
+            _ => data[pos] as f32 / 255.0,
+        }.max(0.0));
+    }
+    let max_density = densities.iter().cloned().fold(0.0, f32::max);
+
+    Ok(DensityGrid {
+        width,
+        height,
+        depth,
+        densities,
+        max_density,
+        bounds,
+        to_local: Matrix3::identity(),
+        to_local_translate: Vector3::new(0.0, 0.0, 0.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* a 2x1x1 grid of bytes with one channel, in the box from 0 to 1 */
+    fn vol(voxels: &[u8]) -> Vec<u8> {
+        let mut data = b"VOL\x03".to_vec();
+        for &value in &[3u32, 2, 1, 1, 1] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for &value in &[0.0f32, 0.0, 0.0, 1.0, 1.0, 1.0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(voxels);
+        data
+    }
+
+    #[test]
+    fn byte_grid() {
+        let grid = load_vol(&vol(&[0, 255])).unwrap();
+        assert_eq!((grid.width, grid.height, grid.depth), (2, 1, 1));
+        assert_eq!(grid.densities, vec![0.0, 1.0]);
+        assert_eq!(grid.max_density, 1.0);
+        assert_eq!(grid.density(Vector3::new(0.5, 0.5, 0.5)), 0.5);
+        assert_eq!(grid.density(Vector3::new(2.0, 0.5, 0.5)), 0.0);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = vol(&[0, 255]);
+        assert!(load_vol(&data[..40]).is_err());
+        assert!(load_vol(&data[..3]).is_err());
+    }
+
+    #[test]
+    fn truncated_voxels() {
+        assert!(load_vol(&vol(&[0])).is_err());
+    }
+
+    #[test]
+    fn overflowing_size() {
+        let mut data = vol(&[0, 255]);
+        for pos in &[8, 12, 16, 20] {
+            data[*pos..*pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        }
+        assert!(load_vol(&data).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+extern crate inflate;
+
+use std::fs::File;
+use std::io::prelude::*;
+
+/* high dynamic range images, as width, height and linear rgb floats row by row from the top */
+pub struct HdrImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<f32>,
+}
+
+pub fn load_hdr_image(filename: &str) -> HdrImage {
+    let mut f = File::open(filename).expect("image not found");
+    let mut data = Vec::new();
+    f.read_to_end(&mut data).expect("could not read image");
+
+    if filename.ends_with(".exr") {
+        load_exr(&data).unwrap_or_else(|err| panic!("could not read {}: {}", filename, err))
+    } else {
+        load_rgbe(&data).unwrap_or_else(|err| panic!("could not read {}: {}", filename, err))
+    }
+}
+
+/* radiance .hdr files: a text header, then rgbe pixels, usually run length encoded per channel */
+fn load_rgbe(data: &[u8]) -> Result<HdrImage, String> {
+    let mut pos = 0;
+    let mut next_line = || -> Result<String, String> {
+        let start = pos;
+        while pos < data.len() && data[pos] != b'\n' {
+            pos += 1;
+        }
+        if pos >= data.len() {
+            return Err("header ended early".to_string());
+        }
+        pos += 1;
+        Ok(String::from_utf8_lossy(&data[start..pos - 1]).trim().to_string())
+    };
+
+    let magic = next_line()?;
+    if !magic.starts_with("#?") {
+        return Err("not a radiance hdr file".to_string());
+    }
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(format!("unsupported {}", line));
+        }
+    }
+
+    let resolution = next_line()?;
+    let parts: Vec<&str> = resolution.split_whitespace().collect();
+    if parts.len() != 4 || parts[0] != "-Y" || parts[2] != "+X" {
+        return Err(format!("unsupported image orientation {}", resolution));
+    }
+    let height: usize = parts[1].parse().map_err(|_| "could not parse image height".to_string())?;
+    let width: usize = parts[3].parse().map_err(|_| "could not parse image width".to_string())?;
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    let mut scanline = vec![0u8; width * 4];
+    for _ in 0..height {
+        let rest = &data[pos..];
+        let rle = (8..32768).contains(&width) && rest.len() >= 4 && rest[0] == 2 && rest[1] == 2
+            && ((rest[2] as usize) << 8 | rest[3] as usize) == width;
+
+        if rle {
+            pos += 4;
+            /* each channel is stored separately, as runs and literal stretches */
+            for channel in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = *data.get(pos).ok_or("image data ended early")? as usize;
+                    pos += 1;
+                    if count > 128 {
+                        let value = *data.get(pos).ok_or("image data ended early")?;
+                        pos += 1;
+                        for _ in 0..count - 128 {
+                            if x >= width {
+                                return Err("run goes past the end of the scanline".to_string());
+                            }
+                            scanline[x * 4 + channel] = value;
+                            x += 1;
+                        }
+                    } else {
+                        if count == 0 || x + count > width || pos + count > data.len() {
+                            return Err("bad run in image data".to_string());
+                        }
+                        for i in 0..count {
+                            scanline[(x + i) * 4 + channel] = data[pos + i];
+                        }
+                        pos += count;
+                        x += count;
+                    }
+                }
+            }
+        } else {
+            if pos + width * 4 > data.len() {
+                return Err("image data ended early".to_string());
+            }
+            scanline.copy_from_slice(&data[pos..pos + width * 4]);
+            pos += width * 4;
+        }
+
+        for rgbe in scanline.chunks(4) {
+            if rgbe[3] == 0 {
+                pixels.extend_from_slice(&[0.0, 0.0, 0.0]);
+            } else {
+                let scale = 2.0f32.powi(rgbe[3] as i32 - 136);
+                pixels.extend_from_slice(&[rgbe[0] as f32 * scale, rgbe[1] as f32 * scale, rgbe[2] as f32 * scale]);
+            }
+        }
+    }
+
+    Ok(HdrImage { width, height, pixels })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExrPixelType {
+    Uint,
+    Half,
+    Float,
+}
+
+struct ExrChannel {
+    name: String,
+    pixel_type: ExrPixelType,
+}
+
+/* single part scanline openexr files, uncompressed or with rle, zips or zip compression */
+fn load_exr(data: &[u8]) -> Result<HdrImage, String> {
+    if data.len() < 8 || data[0..4] != [0x76, 0x2f, 0x31, 0x01] {
+        return Err("not an openexr file".to_string());
+    }
+    let flags = read_i32(data, 4)? as u32;
+    if flags & 0x200 != 0 {
+        return Err("tiled openexr files aren't supported".to_string());
+    }
+    if flags & 0x1800 != 0 {
+        return Err("deep and multi-part openexr files aren't supported".to_string());
+    }
+
+    let mut pos = 8;
+    let mut channels = Vec::new();
+    let mut compression = 0;
+    let mut data_window = None;
+    loop {
+        let name = read_string(data, &mut pos)?;
+        if name.is_empty() {
+            break;
+        }
+        let _attribute_type = read_string(data, &mut pos)?;
+        let size = read_i32(data, pos)? as u32 as usize;
+        pos += 4;
+        let value = pos.checked_add(size).and_then(|end| data.get(pos..end)).ok_or("header ended early")?;
+        pos += size;
+
+        match name.as_ref() {
+            "channels" => {
+                let mut channel_pos = 0;
+                loop {
+                    let channel_name = read_string(value, &mut channel_pos)?;
+                    if channel_name.is_empty() {
+                        break;
+                    }
+                    let pixel_type = match read_i32(value, channel_pos)? {
+                        0 => ExrPixelType::Uint,
+                        1 => ExrPixelType::Half,
+                        2 => ExrPixelType::Float,
+                        _ => return Err("unknown channel type".to_string()),
+                    };
+                    let x_sampling = read_i32(value, channel_pos + 8)?;
+                    let y_sampling = read_i32(value, channel_pos + 12)?;
+                    if x_sampling != 1 || y_sampling != 1 {
+                        return Err("subsampled channels aren't supported".to_string());
+                    }
+                    channel_pos += 16;
+                    channels.push(ExrChannel { name: channel_name, pixel_type });
+                }
+            },
+            "compression" => {
+                compression = *value.first().ok_or("empty compression attribute")?;
+            },
+            "dataWindow" => {
+                data_window = Some((read_i32(value, 0)?, read_i32(value, 4)?, read_i32(value, 8)?, read_i32(value, 12)?));
+            },
+            _ => {}
+        }
+    }
+
+    let (x_min, y_min, x_max, y_max) = data_window.ok_or("no data window in header")?;
+    if x_max < x_min || y_max < y_min {
+        return Err("empty data window".to_string());
+    }
+    /* as i64 so a window spanning most of the i32 range doesn't overflow */
+    let width = (x_max as i64 - x_min as i64 + 1) as usize;
+    let height = (y_max as i64 - y_min as i64 + 1) as usize;
+    let lines_per_block = match compression {
+        0..=2 => 1,
+        3 => 16,
+        _ => return Err(format!("compression type {} isn't supported", compression)),
+    };
+
+    let bytes_per_pixel: usize = channels.iter().map(|channel| if channel.pixel_type == ExrPixelType::Half { 2 } else { 4 }).sum();
+    let find = |names: &[&str]| channels.iter().position(|channel| names.contains(&channel.name.as_ref()));
+    let grey = find(&["Y"]);
+    let rgb = [find(&["R"]).or(grey), find(&["G"]).or(grey), find(&["B"]).or(grey)];
+
+    /* the offset table has to fit in the file, which also keeps a made up height from
+     * allocating more than the file could fill */
+    let num_blocks = height.div_ceil(lines_per_block);
+    if num_blocks.checked_mul(8).and_then(|size| size.checked_add(pos)).is_none_or(|end| end > data.len()) {
+        return Err("file ended early".to_string());
+    }
+    let line_size = width.checked_mul(bytes_per_pixel).ok_or("image is too large")?;
+    let mut pixels = vec![0.0; width.checked_mul(height).and_then(|size| size.checked_mul(3)).ok_or("image is too large")?];
+    for block in 0..num_blocks {
+        let offset = read_u64(data, pos + 8 * block)?;
+        if offset > data.len() as u64 {
+            return Err("block offset past the end of the file".to_string());
+        }
+        let offset = offset as usize;
+        let y = read_i32(data, offset)? as i64 - y_min as i64;
+        if y < 0 || y >= height as i64 {
+            return Err("block outside of the data window".to_string());
+        }
+        let y = y as usize;
+        let size = read_i32(data, offset + 4)? as u32 as usize;
+        let chunk = (offset + 8).checked_add(size).and_then(|end| data.get(offset + 8..end)).ok_or("image data ended early")?;
+
+        let lines = lines_per_block.min(height - y);
+        let expected = lines.checked_mul(line_size).ok_or("image is too large")?;
+        /* blocks that wouldn't get any smaller are stored as they are */
+        let raw = if size == expected || compression == 0 {
+            chunk.to_vec()
+        } else if compression == 1 {
+            unpredict(decode_exr_rle(chunk, expected)?)
+        } else {
+            unpredict(inflate::inflate_bytes_zlib(chunk)?)
+        };
+        if raw.len() < expected {
+            return Err("block is too short".to_string());
+        }
+
+        /* each line holds all of one channel, then all of the next, in the order of the header */
+        let mut line_pos = 0;
+        for line in 0..lines {
+            let row = y + line;
+            for (c, channel) in channels.iter().enumerate() {
+                for x in 0..width {
+                    let value = match channel.pixel_type {
+                        ExrPixelType::Half => {
+                            let bytes = raw.get(line_pos..line_pos + 2).ok_or("block is too short")?;
+                            let bits = bytes[0] as u16 | (bytes[1] as u16) << 8;
+                            line_pos += 2;
+                            half_to_f32(bits)
+                        },
+                        ExrPixelType::Float => {
+                            let value = f32::from_bits(read_i32(&raw, line_pos)? as u32);
+                            line_pos += 4;
+                            value
+                        },
+                        ExrPixelType::Uint => {
+                            let value = read_i32(&raw, line_pos)? as u32 as f32;
+                            line_pos += 4;
+                            value
+                        },
+                    };
+                    let pixel = pixels.get_mut((row * width + x) * 3..(row * width + x) * 3 + 3).ok_or("pixel outside of the image")?;
+                    for (i, component) in pixel.iter_mut().enumerate() {
+                        if rgb[i] == Some(c) {
+                            *component = value;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(HdrImage { width, height, pixels })
+}
+
+fn decode_exr_rle(data: &[u8], expected: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(expected);
+    let mut pos = 0;
+    while pos < data.len() {
+        let count = data[pos] as i8;
+        pos += 1;
+        if count < 0 {
+            let count = (-(count as i32)) as usize;
+            if pos + count > data.len() {
+                return Err("bad run in image data".to_string());
+            }
+            out.extend_from_slice(&data[pos..pos + count]);
+            pos += count;
+        } else {
+            let value = *data.get(pos).ok_or("bad run in image data")?;
+            pos += 1;
+            for _ in 0..count as usize + 1 {
+                out.push(value);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/* undoes the delta coding and byte splitting rle and zip compression do before compressing */
+fn unpredict(mut data: Vec<u8>) -> Vec<u8> {
+    for i in 1..data.len() {
+        data[i] = (data[i - 1] as i32 + data[i] as i32 - 128) as u8;
+    }
+
+    let half = data.len().div_ceil(2);
+    let mut out = Vec::with_capacity(data.len());
+    for i in 0..half {
+        out.push(data[i]);
+        if half + i < data.len() {
+            out.push(data[half + i]);
+        }
+    }
+    out
+}
+
+fn half_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2.0f32.powi(-24),
+        31 => if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN },
+        _ => sign * (1.0 + mantissa / 1024.0) * 2.0f32.powi(exponent - 15),
+    }
+}
+
+fn read_i32(data: &[u8], pos: usize) -> Result<i32, String> {
+    let bytes = pos.checked_add(4).and_then(|end| data.get(pos..end)).ok_or("file ended early")?;
+    Ok(bytes[0] as i32 | (bytes[1] as i32) << 8 | (bytes[2] as i32) << 16 | (bytes[3] as i32) << 24)
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<u64, String> {
+    let low = read_i32(data, pos)? as u32 as u64;
+    let high = read_i32(data, pos.checked_add(4).ok_or("file ended early")?)? as u32 as u64;
+    Ok(low | high << 32)
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Result<String, String> {
+    let start = *pos;
+    while *pos < data.len() && data[*pos] != 0 {
+        *pos += 1;
+    }
+    if *pos >= data.len() {
+        return Err("file ended early".to_string());
+    }
+    *pos += 1;
+    Ok(String::from_utf8_lossy(&data[start..*pos - 1]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgbe_rle_scanline() {
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        /* red a run of 128, green written out, blue a run of 0 and the exponent a run of 129 */
+        data.extend_from_slice(&[128 + 8, 128]);
+        data.extend_from_slice(&[8, 0, 16, 32, 48, 64, 80, 96, 112]);
+        data.extend_from_slice(&[128 + 8, 0]);
+        data.extend_from_slice(&[128 + 8, 129]);
+
+        let image = load_rgbe(&data).unwrap();
+        assert_eq!((image.width, image.height), (8, 1));
+        for x in 0..8 {
+            assert_eq!(&image.pixels[x * 3..x * 3 + 3], &[1.0, x as f32 / 8.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn rgbe_truncated() {
+        let mut data = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+        data.extend_from_slice(&[2, 2, 0, 8, 128 + 8, 128]);
+        assert!(load_rgbe(&data).is_err());
+    }
+
+    /* a zip compressed openexr file with one line of two half floats in a Y channel, the
+     * block claiming to start at line block_y */
+    fn zip_exr(block_y: i32) -> Vec<u8> {
+        let line = [0x00, 0x3c, 0x00, 0xc0];
+
+        /* what zip compression does before deflating: split the bytes into every other one and
+         * store the differences */
+        let split = [line[0], line[2], line[1], line[3]];
+        let mut predicted = split.to_vec();
+        for i in 1..split.len() {
+            predicted[i] = (split[i] as i32 - split[i - 1] as i32 + 128) as u8;
+        }
+
+        /* a zlib stream holding one stored deflate block */
+        let mut zlib = vec![0x78, 0x01, 0x01, predicted.len() as u8, 0, !(predicted.len() as u8), 0xff];
+        zlib.extend_from_slice(&predicted);
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in &predicted {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+
+        let mut data = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+        let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+            data.extend_from_slice(kind.as_bytes());
+            data.push(0);
+            data.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            data.extend_from_slice(value);
+        };
+        let mut channels = b"Y\0".to_vec();
+        for &value in &[1i32, 0, 1, 1] {
+            channels.extend_from_slice(&value.to_le_bytes());
+        }
+        channels.push(0);
+        attribute("channels", "chlist", &channels);
+        attribute("compression", "compression", &[3]);
+        let mut window = Vec::new();
+        for &value in &[0i32, 0, 1, 0] {
+            window.extend_from_slice(&value.to_le_bytes());
+        }
+        attribute("dataWindow", "box2i", &window);
+        data.push(0);
+
+        let offset = data.len() + 8;
+        data.extend_from_slice(&(offset as u64).to_le_bytes());
+        data.extend_from_slice(&block_y.to_le_bytes());
+        data.extend_from_slice(&(zlib.len() as i32).to_le_bytes());
+        data.extend_from_slice(&zlib);
+        data
+    }
+
+    #[test]
+    fn exr_zip_block() {
+        let image = load_exr(&zip_exr(0)).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixels, vec![1.0, 1.0, 1.0, -2.0, -2.0, -2.0]);
+    }
+
+    #[test]
+    fn exr_malformed() {
+        assert!(load_exr(&zip_exr(1)).is_err());
+        assert!(load_exr(&zip_exr(-1)).is_err());
+        let data = zip_exr(0);
+        assert!(load_exr(&data[..data.len() - 1]).is_err());
+
+        /* the offset table's one entry points just past itself. make it point past the end */
+        let mut data = zip_exr(0);
+        let table = (0..data.len() - 8).find(|&i| data[i..i + 8] == ((i + 8) as u64).to_le_bytes()).unwrap();
+        data[table..table + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(load_exr(&data).is_err());
+    }
+
+    #[test]
+    fn half_floats() {
+        assert_eq!(half_to_f32(0x3c00), 1.0);
+        assert_eq!(half_to_f32(0xc000), -2.0);
+        assert_eq!(half_to_f32(0x3555), 0.33325195);
+        assert_eq!(half_to_f32(0x7bff), 65504.0);
+        assert_eq!(half_to_f32(0x0001), 2.0f32.powi(-24));
+        assert_eq!(half_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(half_to_f32(0x7e00).is_nan());
+    }
+}
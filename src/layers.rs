@@ -0,0 +1,88 @@
+extern crate cgmath;
+
+use scene::*;
+
+use std::f32::consts;
+use self::cgmath::{Vector3, InnerSpace};
+
+/* a clear dielectric coat over the rest of a material, like varnish or the clearcoat on car
+ * paint, with its own index of refraction and glossiness */
+#[derive(Debug, Clone)]
+pub struct Clearcoat {
+    pub index: f32,
+    pub glossiness: f32,
+}
+
+/* the soft shine of fabric towards grazing angles: the "Charlie" sheen of Estevez and Kulla
+ * (2017) with Neubelt and Pettineo's visibility term. roughness runs from 0 (only right at the
+ * edge) to 1 (spread over the whole surface) */
+#[derive(Debug, Clone)]
+pub struct Sheen {
+    pub color: Color,
+    pub roughness: f32,
+    /* how much light the sheen reflects in all, by the cosine of the angle it's seen at */
+    albedo: [f32; SHEEN_ALBEDO_SIZE],
+}
+
+const SHEEN_ALBEDO_SIZE: usize = 32;
+
+impl Clearcoat {
+    /* Schlick's approximation for the coat's Fresnel reflectance */
+    pub fn reflectance(&self, cos: f32) -> f32 {
+        let r0 = ((1.0 - self.index) / (1.0 + self.index)).powi(2);
+        r0 + (1.0 - r0) * (1.0 - cos.clamp(0.0, 1.0)).powi(5)
+    }
+}
+
+impl Sheen {
+    pub fn new(color: Color, roughness: f32) -> Sheen {
+        let mut sheen = Sheen {
+            color,
+            roughness: roughness.clamp(0.05, 1.0),
+            albedo: [0.0; SHEEN_ALBEDO_SIZE],
+        };
+
+        /* integrated over the hemisphere once, so the layers below know what's left for them */
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let (rows, columns) = (32, 64);
+        for i in 0..SHEEN_ALBEDO_SIZE {
+            let cos = (i as f32 + 0.5) / SHEEN_ALBEDO_SIZE as f32;
+            let view = Vector3::new((1.0 - cos * cos).sqrt(), 0.0, cos);
+            let mut albedo = 0.0;
+            for row in 0..rows {
+                let theta = (row as f32 + 0.5) / rows as f32 * consts::PI / 2.0;
+                for column in 0..columns {
+                    let phi = (column as f32 + 0.5) / columns as f32 * 2.0 * consts::PI;
+                    let light = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                    let solid_angle = theta.sin() * (consts::PI / 2.0 / rows as f32) * (2.0 * consts::PI / columns as f32);
+                    albedo += sheen.brdf(normal, view, light) * theta.cos() * solid_angle;
+                }
+            }
+            sheen.albedo[i] = albedo.min(1.0);
+        }
+        sheen
+    }
+
+    /* the brdf without its color, for light arriving along light and leaving along view */
+    pub fn brdf(&self, normal: Vector3<f32>, view: Vector3<f32>, light: Vector3<f32>) -> f32 {
+        let (cos_view, cos_light) = (normal.dot(view), normal.dot(light));
+        if cos_view <= 0.0 || cos_light <= 0.0 {
+            return 0.0;
+        }
+        let half = (view + light).normalize();
+        let cos_half = normal.dot(half);
+        let sin_sqr = (1.0 - cos_half * cos_half).max(0.0);
+        let distribution = (2.0 + 1.0 / self.roughness) * sin_sqr.powf(0.5 / self.roughness) / (2.0 * consts::PI);
+        let visibility = 1.0 / (4.0 * (cos_light + cos_view - cos_light * cos_view));
+        distribution * visibility
+    }
+
+    /* the fraction of light reflected when seen at an angle with cosine cos */
+    pub fn albedo(&self, cos: f32) -> f32 {
+        let x = (cos.clamp(0.0, 1.0) * SHEEN_ALBEDO_SIZE as f32 - 0.5).max(0.0);
+        let i = (x as usize).min(SHEEN_ALBEDO_SIZE - 1);
+        let next = (i + 1).min(SHEEN_ALBEDO_SIZE - 1);
+        let t = (x - i as f32).min(1.0);
+        self.albedo[i] + (self.albedo[next] - self.albedo[i]) * t
+    }
+}
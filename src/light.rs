@@ -0,0 +1,358 @@
+extern crate cgmath;
+
+use scene::*;
+use geometry::*;
+use sampler::{concentric_disk_sample, Distribution2D};
+
+use std::collections::HashMap;
+use std::f32::consts;
+use self::cgmath::{Vector3, Matrix3, InnerSpace, Zero, One};
+
+/* an object with an emissive material, in world space, that direct lighting can aim at */
+#[derive(Debug)]
+pub struct Emitter {
+    pub shape: EmitterShape,
+    pub emission: Color,
+}
+
+#[derive(Debug)]
+pub enum EmitterShape {
+    /* a transformed plane: corner + s * edge1 + t * edge2 for s, t in [0, 1] */
+    Parallelogram { corner: Vector3<f32>, edge1: Vector3<f32>, edge2: Vector3<f32> },
+    /* a transformed disk: center + s * axis1 + t * axis2 for s^2 + t^2 <= 1 */
+    Ellipse { center: Vector3<f32>, axis1: Vector3<f32>, axis2: Vector3<f32> },
+    Sphere { center: Vector3<f32>, radius: f32 },
+    /* triangles of a mesh and the cumulative distribution of their areas */
+    Triangles { triangles: Vec<(Vector3<f32>, Vector3<f32>, Vector3<f32>)>, cdf: Vec<f32>, area: f32 },
+}
+
+/* light arriving at a point from one sampled direction */
+pub struct LightSample {
+    pub dir: Vector3<f32>,
+    /* how far along dir the light is, infinite for directional lights */
+    pub distance: f32,
+    /* radiance divided by the probability density of dir, or irradiance for delta lights */
+    pub color: Color,
+}
+
+/* walks the node tree collecting every object with an emissive material */
+pub fn collect_emitters(nodes: &[Node], materials: &HashMap<String, Material>) -> Vec<Emitter> {
+    let mut emitters = Vec::new();
+    for node in nodes {
+        collect_node(node, &Matrix3::one(), Vector3::zero(), materials, &mut emitters);
+    }
+    emitters
+}
+
+fn collect_node(node: &Node, parent_transform: &Matrix3<f32>, parent_translate: Vector3<f32>,
+                materials: &HashMap<String, Material>, emitters: &mut Vec<Emitter>) {
+    let transform = parent_transform * node.transform.transform;
+    let translate = parent_transform * node.transform.translate + parent_translate;
+    let to_world = |p: Vector3<f32>| transform * p + translate;
+
+    if let Some(ref object) = node.object {
+        let material = materials.get(&object.material[..]).expect("material does not exist for object");
+        /* emission that changes over the surface is only found by rays that hit it */
+        let emission = material.emission.color;
+        if material.emission.is_constant() && (emission.x > 0.0 || emission.y > 0.0 || emission.z > 0.0) {
+            let shape = match object.geometry {
+                Geometry::Plane => EmitterShape::Parallelogram {
+                    corner: to_world(Vector3::new(-1.0, -1.0, 0.0)),
+                    edge1: transform * Vector3::new(2.0, 0.0, 0.0),
+                    edge2: transform * Vector3::new(0.0, 2.0, 0.0),
+                },
+                Geometry::Disk => EmitterShape::Ellipse {
+                    center: to_world(Vector3::zero()),
+                    axis1: transform * Vector3::unit_x(),
+                    axis2: transform * Vector3::unit_y(),
+                },
+                Geometry::Sphere => {
+                    let radius = (transform * Vector3::unit_x()).magnitude();
+                    let y = (transform * Vector3::unit_y()).magnitude();
+                    let z = (transform * Vector3::unit_z()).magnitude();
+                    if (y - radius).abs() > 1.0e-3 * radius || (z - radius).abs() > 1.0e-3 * radius {
+                        panic!("emissive spheres have to be scaled the same along every axis");
+                    }
+                    EmitterShape::Sphere { center: to_world(Vector3::zero()), radius }
+                },
+                Geometry::Mesh(ref mesh) => {
+                    let triangles: Vec<_> = mesh.triangles.iter()
+                        .map(|&(a, b, c)| (to_world(mesh.vertices[a]), to_world(mesh.vertices[b]), to_world(mesh.vertices[c])))
+                        .collect();
+                    let mut cdf = Vec::with_capacity(triangles.len());
+                    let mut area = 0.0;
+                    for &(a, b, c) in &triangles {
+                        area += 0.5 * (b - a).cross(c - a).magnitude();
+                        cdf.push(area);
+                    }
+                    for value in &mut cdf {
+                        *value /= area;
+                    }
+                    EmitterShape::Triangles { triangles, cdf, area }
+                },
+                Geometry::Volume(_) => panic!("volumes can't be emissive"),
+            };
+            emitters.push(Emitter { shape, emission });
+        }
+    }
+
+    for child in &node.children {
+        collect_node(child, &transform, translate, materials, emitters);
+    }
+}
+
+impl Emitter {
+    /* picks a point on the emitter as seen from pos. emitters shine from both sides */
+    pub fn sample(&self, pos: Vector3<f32>, sample: (f32, f32)) -> Option<LightSample> {
+        let (point, normal, area) = match self.shape {
+            EmitterShape::Parallelogram { corner, edge1, edge2 } => {
+                let normal = edge1.cross(edge2);
+                (corner + sample.0 * edge1 + sample.1 * edge2, normal.normalize(), normal.magnitude())
+            },
+            EmitterShape::Ellipse { center, axis1, axis2 } => {
+                /* an affine image of a uniform disk sample is uniform on the ellipse */
+                let (s, t) = concentric_disk_sample(sample);
+                let normal = axis1.cross(axis2);
+                (center + s * axis1 + t * axis2, normal.normalize(), consts::PI * normal.magnitude())
+            },
+            EmitterShape::Sphere { center, radius } => {
+                if (center - pos).magnitude2() > radius * radius {
+                    return self.sample_sphere(pos, center, radius, sample);
+                }
+                /* from inside, all of the sphere is in view, so it's sampled by area */
+                let z = 1.0 - 2.0 * sample.0;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * consts::PI * sample.1;
+                let normal = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+                (center + radius * normal, normal, 4.0 * consts::PI * radius * radius)
+            },
+            EmitterShape::Triangles { ref triangles, ref cdf, area } => {
+                let i = cdf.partition_point(|&c| c <= sample.0).min(cdf.len() - 1);
+                let start = if i == 0 { 0.0 } else { cdf[i - 1] };
+                let u = ((sample.0 - start) / (cdf[i] - start)).clamp(0.0, 1.0);
+
+                let (a, b, c) = triangles[i];
+                let su = u.sqrt();
+                let point = (1.0 - su) * a + su * (1.0 - sample.1) * b + su * sample.1 * c;
+                (point, (b - a).cross(c - a).normalize(), area)
+            },
+        };
+
+        let to_light = point - pos;
+        let distance = to_light.magnitude();
+        let dir = to_light / distance;
+        let cos_light = normal.dot(dir).abs();
+        if cos_light < EPSILON || area <= 0.0 {
+            return None;
+        }
+
+        /* uniform over the area, turned into a density over directions */
+        let pdf = distance * distance / (cos_light * area);
+        Some(LightSample { dir, distance, color: self.emission / pdf })
+    }
+
+    /* from outside: uniform over the cone of directions the sphere covers, Shirley et al. 1996 */
+    fn sample_sphere(&self, pos: Vector3<f32>, center: Vector3<f32>, radius: f32, sample: (f32, f32)) -> Option<LightSample> {
+        let to_center = center - pos;
+        let distance_sqr = to_center.magnitude2();
+        let distance = distance_sqr.sqrt();
+        let axis = to_center / distance;
+        let cos_max = (1.0 - radius * radius / distance_sqr).max(0.0).sqrt();
+        let cos_theta = 1.0 - sample.0 * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * consts::PI * sample.1;
+
+        let (u, v) = orthonormal_basis(axis);
+        let dir = (cos_theta * axis + sin_theta * (phi.cos() * u + phi.sin() * v)).normalize();
+
+        /* the near side of the sphere along dir */
+        let b = dir.dot(to_center);
+        let hit = b - (b * b - distance_sqr + radius * radius).max(0.0).sqrt();
+
+        let pdf = 1.0 / (2.0 * consts::PI * (1.0 - cos_max));
+        Some(LightSample { dir, distance: hit, color: self.emission / pdf })
+    }
+}
+
+/* the environment as a light, importance sampled by brightness */
+#[derive(Debug)]
+pub struct EnvironmentLight {
+    distribution: Distribution2D,
+}
+
+impl EnvironmentLight {
+    /* None for an environment that's black everywhere */
+    pub fn new(environment: &Texture) -> Option<EnvironmentLight> {
+        /* one cell per texel, and enough cells to pick out the squares of a checkerboard */
+        let (width, height) = match environment.data {
+            TextureData::Image { width, height, .. } | TextureData::HdrImage { width, height, .. } => (width, height),
+            TextureData::Checkerboard { .. } | TextureData::Sky(_) => (256, 128),
+            TextureData::Blank => (64, 32),
+        };
+
+        /* rows near the poles cover less of the sphere */
+        let mut weights = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let v = (y as f32 + 0.5) / height as f32;
+            let cos_latitude = ((v - 0.5) * consts::PI).cos();
+            for x in 0..width {
+                let u = (x as f32 + 0.5) / width as f32;
+                weights.push(luminance(environment.sample(Vector3::new(u, v, 0.0))) * cos_latitude);
+            }
+        }
+
+        Distribution2D::new(width, height, &weights).map(|distribution| EnvironmentLight { distribution })
+    }
+
+    /* a direction and its density over solid angle */
+    pub fn sample(&self, sample: (f32, f32)) -> Option<(Vector3<f32>, f32)> {
+        let ((u, v), pdf) = self.distribution.sample(sample);
+        let dir = environment_dir(u, v);
+        let cos_latitude = (1.0 - dir.z * dir.z).max(0.0).sqrt();
+        if pdf <= 0.0 || cos_latitude <= 0.0 {
+            return None;
+        }
+        Some((dir, pdf / (2.0 * consts::PI * consts::PI * cos_latitude)))
+    }
+
+    pub fn pdf(&self, dir: Vector3<f32>) -> f32 {
+        let (u, v) = environment_uv(dir);
+        let cos_latitude = (1.0 - dir.z * dir.z).max(0.0).sqrt();
+        if cos_latitude <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * consts::PI * consts::PI * cos_latitude)
+    }
+}
+
+/* a photometric web from an IES LM-63 file, type C: vertical angles measured from straight down
+ * and horizontal angles around it, both in degrees, in the frame given by down and across */
+#[derive(Debug, Clone)]
+pub struct IesProfile {
+    pub vertical_angles: Vec<f32>,
+    pub horizontal_angles: Vec<f32>,
+    /* candela for each horizontal angle, for each vertical angle */
+    pub candela: Vec<f32>,
+    pub down: Vector3<f32>,
+    pub across: Vector3<f32>,
+}
+
+impl Light {
+    /* light from a point, spot or directional light, None for ambient light which comes from everywhere */
+    pub fn sample(&self, pos: Vector3<f32>, sample: (f32, f32)) -> Option<LightSample> {
+        let (position, falloff) = match self.light_type {
+            LightType::Ambient => return None,
+            LightType::Directional { direction, cos_radius } => {
+                /* a disk of light is sampled uniformly over the cone it fills, which gives
+                 * back its illuminance */
+                let dir = if cos_radius < 1.0 {
+                    let (u, v) = sample;
+                    let cos = 1.0 - u * (1.0 - cos_radius);
+                    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                    let phi = 2.0 * consts::PI * v;
+                    let (x, y) = orthonormal_basis(-direction);
+                    (sin * phi.cos() * x + sin * phi.sin() * y - cos * direction).normalize()
+                } else {
+                    -direction
+                };
+                return Some(LightSample {
+                    dir,
+                    distance: f32::INFINITY,
+                    color: self.intensity * self.color,
+                });
+            },
+            LightType::Point { position } => (position, 1.0),
+            LightType::Spot { position, direction, cos_inner, cos_outer } => {
+                let cos = direction.dot((pos - position).normalize());
+                (position, smooth_step(cos_outer, cos_inner, cos))
+            },
+        };
+
+        let to_light = position - pos;
+        let distance = to_light.magnitude();
+        let dir = to_light / distance;
+        let profile = self.profile.as_ref().map_or(1.0, |profile| profile.candela(-dir));
+        if falloff * profile <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            dir,
+            distance,
+            color: falloff * profile * self.intensity * self.color / (distance * distance),
+        })
+    }
+}
+
+impl IesProfile {
+    /* candela in direction dir, going away from the light */
+    pub fn candela(&self, dir: Vector3<f32>) -> f32 {
+        let vertical = dir.dot(self.down).clamp(-1.0, 1.0).acos().to_degrees();
+
+        let side = self.down.cross(self.across);
+        let mut horizontal = dir.dot(side).atan2(dir.dot(self.across)).to_degrees();
+        if horizontal < 0.0 {
+            horizontal += 360.0;
+        }
+
+        /* the last horizontal angle says which symmetry the file relies on */
+        let last = *self.horizontal_angles.last().unwrap();
+        if last == 0.0 {
+            horizontal = 0.0;
+        } else if last == 90.0 {
+            if horizontal > 180.0 {
+                horizontal = 360.0 - horizontal;
+            }
+            if horizontal > 90.0 {
+                horizontal = 180.0 - horizontal;
+            }
+        } else if last == 180.0 && horizontal > 180.0 {
+            horizontal = 360.0 - horizontal;
+        }
+
+        let (v, vt) = interpolation_index(&self.vertical_angles, vertical);
+        let (h, ht) = interpolation_index(&self.horizontal_angles, horizontal);
+        let rows = self.vertical_angles.len();
+        let value = |h: usize, v: usize| self.candela[h * rows + v];
+        let h1 = (h + 1).min(self.horizontal_angles.len() - 1);
+        let v1 = (v + 1).min(rows - 1);
+
+        (1.0 - ht) * ((1.0 - vt) * value(h, v) + vt * value(h, v1))
+            + ht * ((1.0 - vt) * value(h1, v) + vt * value(h1, v1))
+    }
+}
+
+/* the entry at or below value in a sorted list and how far it is towards the next, clamped at the ends */
+fn interpolation_index(angles: &[f32], value: f32) -> (usize, f32) {
+    let i = angles.partition_point(|&angle| angle <= value);
+    if i == 0 {
+        return (0, 0.0);
+    }
+    if i == angles.len() {
+        return (angles.len() - 1, 0.0);
+    }
+    let (a, b) = (angles[i - 1], angles[i]);
+    (i - 1, if b > a { (value - a) / (b - a) } else { 0.0 })
+}
+
+fn smooth_step(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge1 <= edge0 {
+        return if x >= edge1 { 1.0 } else { 0.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+pub fn orthonormal_basis(vec: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let x_abs = vec.x.abs(); let y_abs = vec.y.abs(); let z_abs = vec.z.abs();
+    let smallest_axis = if x_abs < y_abs && x_abs < z_abs {
+        Vector3::unit_x()
+    } else if y_abs < z_abs {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_z()
+    };
+    let u = vec.cross(smallest_axis).normalize();
+    let v = vec.cross(u).normalize();
+    (u, v)
+}
@@ -4,17 +4,36 @@ extern crate wavefront_obj;
 extern crate png;
 
 use scene::*;
+use camera::*;
+use light::*;
+use hdr::*;
+use sky::*;
+use medium::*;
+use grid::*;
+use spectrum::*;
+use fresnel::*;
+use layers::*;
 use geometry::*;
 use bvh::*;
+use sampler::*;
+use render::TileOrder;
 
+use std::f32::consts;
 use std::fs::File;
 use std::io::prelude::*;
 use std::collections::HashMap;
 use self::xmltree::Element;
-use self::cgmath::{Vector3, Matrix3, SquareMatrix, InnerSpace, One, Deg};
+use self::cgmath::{Vector3, Matrix3, SquareMatrix, InnerSpace, ElementWise, One, Deg};
 use self::wavefront_obj::obj;
+use self::png::HasParameters;
 
-pub fn load_scene(filename: &str) -> (Scene, Camera) {
+/* height of a full frame 35mm sensor, in millimetres */
+const SENSOR_HEIGHT: f32 = 24.0;
+
+/* lumens per watt, for light at the peak of the eye's sensitivity */
+const LUMINOUS_EFFICACY: f32 = 683.0;
+
+pub fn load_scene(filename: &str) -> (Scene, Camera, Sampling) {
     let mut f = File::open(filename).expect("file not found");
     let mut contents = String::new();
     f.read_to_string(&mut contents).expect("could not read file");
@@ -55,24 +74,47 @@ pub fn load_scene(filename: &str) -> (Scene, Camera) {
                 materials.insert(name, material);
             },
             "light" => {
-                lights.push(load_light(child));
+                if is_area_light(child) {
+                    /* area lights are objects with an emissive material of their own */
+                    let (node, material) = load_area_light(child);
+                    let name = node.object.as_ref().unwrap().material.clone();
+                    materials.insert(name, material);
+                    nodes.push(node);
+                } else {
+                    lights.push(load_light(child, &environment));
+                }
             }
             _ => {}
         }
     }
 
+    for node in &mut nodes {
+        place_volumes(node, &Matrix3::one(), Vector3::new(0.0, 0.0, 0.0));
+    }
+    let emitters = collect_emitters(&nodes, &materials);
+    let environment_light = EnvironmentLight::new(&environment);
+    let medium = scene_xml.get_child("medium").map(load_medium);
+    let bounds = scene_bounds(&nodes);
+
     let scene = Scene {
         nodes: nodes,
         materials: materials,
         lights: lights,
+        emitters,
         background: background,
         environment: environment,
+        environment_light,
+        medium,
+        bounds,
+        spectral: scene_xml.get_child("spectral").is_some(),
     };
 
     let camera_xml = xml.get_child("camera").expect("no <camera> tag found");
     let camera = load_camera(camera_xml);
 
-    (scene, camera)
+    let sampling = xml.get_child("sampling").map(load_sampling).unwrap_or_default();
+
+    (scene, camera, sampling)
 }
 
 fn load_node(node_xml: &Element) -> Node {
@@ -85,8 +127,22 @@ fn load_node(node_xml: &Element) -> Node {
                 "plane" => {
                     Geometry::Plane
                 },
+                "disk" => {
+                    Geometry::Disk
+                },
                 "obj" => {
-                    load_obj(node_xml.attributes.get("name").expect("no filename given for obj"))
+                    let mut geometry = load_obj(node_xml.attributes.get("name").expect("no filename given for obj"));
+                    if let (Some(displacement_xml), &mut Geometry::Mesh(ref mut mesh)) = (node_xml.get_child("displacement"), &mut geometry) {
+                        let subdivisions = displacement_xml.attributes.get("subdivisions")
+                            .map(|s| s.parse().expect("could not parse displacement subdivisions"))
+                            .unwrap_or(0);
+                        mesh.subdivide(subdivisions);
+                        mesh.displace(&load_texture(displacement_xml, Vector3::new(0.01, 0.01, 0.01)));
+                    }
+                    geometry
+                },
+                "volume" => {
+                    Geometry::Volume(Box::new(load_density_grid(node_xml.attributes.get("name").expect("no filename given for volume"))))
                 }
                 _ => {
                     panic!("unknown object type");
@@ -119,7 +175,7 @@ fn load_material(material_xml: &Element) -> (String, Material) {
     let material_type = material_xml.attributes.get("type").expect("no type for material");
     let name = material_xml.attributes.get("name").expect("no name for material");
 
-    match material_type.as_ref() {
+    let (name, mut material) = match material_type.as_ref() {
         "blinn" => {
             let mut diffuse = Texture {
                 data: TextureData::Blank,
@@ -133,34 +189,31 @@ fn load_material(material_xml: &Element) -> (String, Material) {
             let specular_xml = material_xml.get_child("specular").expect("no specular found for <material>");
             let specular = load_texture(specular_xml, Vector3::new(0.7, 0.7, 0.7));
 
-            let glossiness = if let Some(glossiness_xml) = material_xml.get_child("glossiness") {
-                glossiness_xml.attributes
-                    .get("value").expect("no value found for <glossiness>")
-                    .parse().expect("could not parse glossiness value")
-            } else {
-                20.0
-            };
+            let glossiness = load_parameter(material_xml, "glossiness", 20.0);
 
             let emission = material_xml.get_child("emission").map(|emission_xml| {
-                let maybe_value = emission_xml.attributes.get("value");
-                let maybe_color = read_color(&emission_xml.attributes);
-                if maybe_value.is_some() || maybe_color.is_some() {
-                    maybe_value.and_then(|s| s.parse().ok()).unwrap_or(1.0) * maybe_color.unwrap_or(Vector3::new(1.0, 1.0, 1.0))
-                } else {
-                    Vector3::new(0.0, 0.0, 0.0)
-                }
-            }).unwrap_or(Vector3::new(0.0, 0.0, 0.0));
+                /* an emission map shines as bright as its texels unless given a value */
+                let default = if emission_xml.attributes.contains_key("texture") { 1.0 } else { 0.0 };
+                let mut emission = load_texture(emission_xml, Vector3::new(default, default, default));
+                /* emission is radiance in nits, or what leaves each square meter of the surface */
+                emission.color *= match emission_xml.attributes.get("units").map(|units| units.as_ref()) {
+                    None | Some("nits") => 1.0,
+                    Some("lumens") => 1.0 / consts::PI,
+                    Some("watts") => LUMINOUS_EFFICACY / consts::PI,
+                    Some(_) => panic!("unknown emission units"),
+                };
+                emission
+            }).unwrap_or_else(|| constant_texture(Vector3::new(0.0, 0.0, 0.0)));
 
             let mut reflection = Texture {
                 data: TextureData::Blank,
                 color: Vector3::new(0.0, 0.0, 0.0),
                 transform: Transform::default(),
             };
-            let mut reflection_glossiness = 0.0;
+            let mut reflection_glossiness = constant_texture(Vector3::new(0.0, 0.0, 0.0));
             if let Some(reflection_xml) = material_xml.get_child("reflection") {
                 reflection = load_texture(reflection_xml, Vector3::new(1.0, 1.0, 1.0));
-                reflection_glossiness = reflection_xml.attributes.get("glossiness")
-                    .and_then(|s| s.parse().ok()).unwrap_or(reflection_glossiness);
+                reflection_glossiness = load_parameter(reflection_xml, "glossiness", 0.0);
             }
 
             let mut refraction = Texture {
@@ -168,14 +221,17 @@ fn load_material(material_xml: &Element) -> (String, Material) {
                 color: Vector3::new(0.0, 0.0, 0.0),
                 transform: Transform::default(),
             };
-            let mut refraction_index = 1.0;
-            let mut refraction_glossiness = 0.0;
+            let mut refraction_index = RefractionIndex::Constant(1.0);
+            let mut refraction_index_scale = constant_texture(Vector3::new(1.0, 1.0, 1.0));
+            let mut refraction_glossiness = constant_texture(Vector3::new(0.0, 0.0, 0.0));
             if let Some(refraction_xml) = material_xml.get_child("refraction") {
                 refraction = load_texture(refraction_xml, Vector3::new(1.0, 1.0, 1.0));
-                refraction_index = refraction_xml.attributes.get("index")
-                    .and_then(|s| s.parse().ok()).unwrap_or(refraction_index);
-                refraction_glossiness = refraction_xml.attributes.get("glossiness")
-                    .and_then(|s| s.parse().ok()).unwrap_or(refraction_glossiness);
+                refraction_index = load_refraction_index(refraction_xml).unwrap_or(refraction_index);
+                /* an <index> texture multiplies the index the attributes give, 1 by default */
+                if let Some(index_xml) = refraction_xml.get_child("index") {
+                    refraction_index_scale = load_texture(index_xml, Vector3::new(1.0, 1.0, 1.0));
+                }
+                refraction_glossiness = load_parameter(refraction_xml, "glossiness", 0.0);
             }
 
             let absorption = material_xml.get_child("absorption").and_then(|absorption_xml| {
@@ -192,50 +248,526 @@ fn load_material(material_xml: &Element) -> (String, Material) {
                 refraction: refraction,
                 refraction_glossiness: refraction_glossiness,
                 refraction_index: refraction_index,
+                refraction_index_scale,
                 absorption: absorption,
+                medium: material_xml.get_child("medium").map(load_medium),
+                subsurface: false,
+                conductor: None,
+                clearcoat: material_xml.get_child("clearcoat").map(load_clearcoat),
+                sheen: material_xml.get_child("sheen").map(load_sheen),
+                normal_map: None,
+                bump: None,
+                opacity: None,
+            })
+        },
+        "volume" => {
+            /* nothing but a medium inside an invisible surface */
+            (name.clone(), Material {
+                medium: Some(load_medium(material_xml)),
+                ..emissive_material(Vector3::new(0.0, 0.0, 0.0))
+            })
+        },
+        "subsurface" => {
+            /* albedo is the color a thick block of the material ends up, and distance the mean
+             * free path inside it in meters, as a value, a color or both */
+            let albedo = material_xml.get_child("albedo")
+                .map(|albedo_xml| read_color(&albedo_xml.attributes).expect("no color given for subsurface albedo"))
+                .unwrap_or(Vector3::new(0.8, 0.8, 0.8));
+            let distance = material_xml.get_child("distance").map(|distance_xml| {
+                let value: f32 = distance_xml.attributes.get("value")
+                    .map(|value| value.parse().expect("could not parse subsurface distance"))
+                    .unwrap_or(1.0);
+                value * read_color(&distance_xml.attributes).unwrap_or(Vector3::new(1.0, 1.0, 1.0))
+            }).expect("no distance given for subsurface material");
+            if distance.x <= 0.0 || distance.y <= 0.0 || distance.z <= 0.0 {
+                panic!("subsurface distance must be positive");
+            }
+
+            /* Chiang et al.'s fit from the albedo of the whole walk to that of a single scattering */
+            let single_albedo = |albedo: f32| {
+                let albedo = albedo.clamp(0.0, 1.0);
+                let s = 4.09712 + 4.20863 * albedo - (9.59217 + 41.6808 * albedo + 17.7126 * albedo * albedo).sqrt();
+                1.0 - s * s
+            };
+            let extinction = Vector3::new(1.0 / distance.x, 1.0 / distance.y, 1.0 / distance.z);
+            let scattering = Vector3::new(single_albedo(albedo.x), single_albedo(albedo.y), single_albedo(albedo.z))
+                .mul_element_wise(extinction);
+
+            (name.clone(), Material {
+                reflection_glossiness: load_parameter(material_xml, "glossiness", 0.0),
+                refraction_index_scale: load_parameter(material_xml, "index", 1.4),
+                medium: Some(Medium {
+                    absorption: extinction - scattering,
+                    scattering,
+                    g: load_g(material_xml),
+                }),
+                subsurface: true,
+                ..emissive_material(Vector3::new(0.0, 0.0, 0.0))
+            })
+        },
+        "conductor" => {
+            /* a metal given by name, or by the real (eta) and imaginary (k) parts of its index
+             * of refraction for red, green and blue */
+            let mut conductor = match material_xml.attributes.get("metal") {
+                Some(metal) => Conductor::preset(metal).unwrap_or_else(|| panic!("unknown metal {}", metal)),
+                None => {
+                    let read_index = |name: &str| material_xml.get_child(name)
+                        .and_then(|index_xml| read_color(&index_xml.attributes))
+                        .unwrap_or_else(|| panic!("no metal or <{}> given for conductor", name));
+                    Conductor { eta: read_index("eta"), k: read_index("k"), film: None }
+                },
+            };
+
+            /* a coating with its thickness in nanometers */
+            conductor.film = material_xml.get_child("film").map(|film_xml| {
+                let read_value = |name: &str| film_xml.attributes.get(name)
+                    .unwrap_or_else(|| panic!("no {} given for <film>", name))
+                    .parse().unwrap_or_else(|_| panic!("could not parse film {}", name));
+                Film { thickness: read_value("thickness"), index: read_value("index") }
+            });
+
+            let mut tint = Texture {
+                data: TextureData::Blank,
+                color: Vector3::new(1.0, 1.0, 1.0),
+                transform: Transform::default(),
+            };
+            let mut glossiness = constant_texture(Vector3::new(0.0, 0.0, 0.0));
+            if let Some(reflection_xml) = material_xml.get_child("reflection") {
+                tint = load_texture(reflection_xml, Vector3::new(1.0, 1.0, 1.0));
+                glossiness = load_parameter(reflection_xml, "glossiness", 0.0);
+            }
+
+            (name.clone(), Material {
+                reflection: tint,
+                reflection_glossiness: glossiness,
+                conductor: Some(conductor),
+                clearcoat: material_xml.get_child("clearcoat").map(load_clearcoat),
+                ..emissive_material(Vector3::new(0.0, 0.0, 0.0))
             })
         },
         _ => {
             panic!("unknown material type");
         }
-    }
+    };
+
+    /* any material's surface can be tilted by a normal map or a bump map, whose value is how
+     * high white is in scene units */
+    material.normal_map = material_xml.get_child("normalmap").map(|normal_map_xml| load_texture(normal_map_xml, Vector3::new(1.0, 1.0, 1.0)));
+    material.bump = material_xml.get_child("bump").map(|bump_xml| load_texture(bump_xml, Vector3::new(0.01, 0.01, 0.01)));
+    material.opacity = material_xml.get_child("opacity").map(|opacity_xml| load_texture(opacity_xml, Vector3::new(1.0, 1.0, 1.0)));
+    (name, material)
 }
 
-fn load_light(light_xml: &Element) -> Light {
+fn load_light(light_xml: &Element, environment: &Texture) -> Light {
     let light_type = light_xml.attributes.get("type").expect("no type for light");
+    if light_type == "sun" {
+        return load_sun(light_xml, environment);
+    }
 
     let intensity_xml = light_xml.get_child("intensity").expect("no intensity given for light");
-    let intensity = intensity_xml.attributes.get("value").expect("no value for light intensity")
+    let intensity: f32 = intensity_xml.attributes.get("value").expect("no value for light intensity")
         .parse().expect("could not parse light intensity value");
     let color = read_color(&intensity_xml.attributes).unwrap_or(Vector3::new(1.0, 1.0, 1.0));
+    let units = intensity_xml.attributes.get("units").map(|units| units.as_ref());
 
     let light_type = match light_type.as_ref() {
         "ambient" => {
             LightType::Ambient
         },
         "direct" => {
-            LightType::Directional(read_vector3(&light_xml.get_child("direction")
-                .expect("no direction given for directional light").attributes)
-                .normalize())
+            LightType::Directional {
+                direction: read_vector3(&light_xml.get_child("direction")
+                    .expect("no direction given for directional light").attributes)
+                    .normalize(),
+                cos_radius: 1.0,
+            }
         },
         "point" => {
             let position = read_vector3(&light_xml.get_child("position")
                 .expect("no position given for positional light").attributes);
-            let size = light_xml.get_child("size")
-                .and_then(|size_xml| size_xml.attributes.get("value"))
-                .and_then(|size| size.parse().ok()).unwrap_or(0.0);
-            LightType::Point { position: position, size: size }
+            LightType::Point { position }
+        },
+        "spot" => {
+            let position = read_vector3(&light_xml.get_child("position")
+                .expect("no position given for spot light").attributes);
+            let direction = read_vector3(&light_xml.get_child("direction")
+                .expect("no direction given for spot light").attributes).normalize();
+            /* angle is the full width of the cone and falloff how far in from its edge the
+             * light starts fading, both in degrees */
+            let angle: f32 = light_xml.get_child("angle")
+                .and_then(|angle_xml| angle_xml.attributes.get("value"))
+                .expect("no angle given for spot light")
+                .parse().expect("could not parse spot light angle");
+            let falloff: f32 = light_xml.get_child("falloff")
+                .and_then(|falloff_xml| falloff_xml.attributes.get("value"))
+                .map(|falloff| falloff.parse().expect("could not parse spot light falloff"))
+                .unwrap_or(0.0);
+            let outer = (0.5 * angle).min(180.0);
+            let inner = (outer - falloff).max(0.0);
+            LightType::Spot {
+                position,
+                direction,
+                cos_inner: inner.to_radians().cos(),
+                cos_outer: outer.to_radians().cos(),
+            }
         },
         _ => {
             panic!("unknown light type");
         }
     };
 
+    /* an ies profile gives the intensity in candela, scaled by the intensity value, and points
+     * the direction of the file's vertical angle 0 along the light's direction */
+    let profile = light_xml.get_child("ies").map(|ies_xml| {
+        let down = match light_type {
+            LightType::Spot { direction, .. } => direction,
+            LightType::Point { .. } => light_xml.get_child("direction")
+                .map(|direction_xml| read_vector3(&direction_xml.attributes).normalize())
+                .unwrap_or(Vector3::new(0.0, 0.0, -1.0)),
+            _ => panic!("only point and spot lights can have an ies profile"),
+        };
+        let (across, _) = orthonormal_basis(down);
+        load_ies(ies_xml.attributes.get("file").expect("no file attribute found on <ies> tag"), down, across)
+    });
+    if profile.is_some() && units.is_some() {
+        panic!("the intensity of a light with an ies profile is a scale without units");
+    }
+
+    /* point lights shine in candela, directional lights in lux and ambient light in nits */
+    let intensity = match (units, light_type) {
+        (None, _) => intensity,
+        (Some("lumens"), LightType::Point { .. }) => intensity / (4.0 * consts::PI),
+        (Some("watts"), LightType::Point { .. }) => LUMINOUS_EFFICACY * intensity / (4.0 * consts::PI),
+        (Some("candela"), LightType::Point { .. }) => intensity,
+        (Some("lumens"), LightType::Spot { cos_inner, cos_outer, .. }) => intensity / spot_solid_angle(cos_inner, cos_outer),
+        (Some("watts"), LightType::Spot { cos_inner, cos_outer, .. }) => LUMINOUS_EFFICACY * intensity / spot_solid_angle(cos_inner, cos_outer),
+        (Some("candela"), LightType::Spot { .. }) => intensity,
+        (Some("lux"), LightType::Directional { .. }) => intensity,
+        (Some("watts"), LightType::Directional { .. }) => LUMINOUS_EFFICACY * intensity,
+        (Some("nits"), LightType::Ambient) => intensity,
+        (Some("watts"), LightType::Ambient) => LUMINOUS_EFFICACY * intensity,
+        (Some(units), _) => panic!("units {} don't make sense for this type of light", units),
+    };
+
     Light {
         intensity: intensity,
         color: color,
         light_type: light_type,
+        profile,
+    }
+}
+
+/* the sun of the sky environment, a disk of light as wide as the real one with the color and
+ * illuminance that make it through the atmosphere. an intensity value scales it */
+fn load_sun(light_xml: &Element, environment: &Texture) -> Light {
+    let sky = match environment.data {
+        TextureData::Sky(ref sky) => sky,
+        _ => panic!("a sun light needs a sky environment"),
+    };
+
+    let mut scale = Vector3::new(1.0, 1.0, 1.0);
+    if let Some(intensity_xml) = light_xml.get_child("intensity") {
+        if intensity_xml.attributes.contains_key("units") {
+            panic!("the intensity of a sun light is a scale without units");
+        }
+        let value: f32 = intensity_xml.attributes.get("value")
+            .map(|value| value.parse().expect("could not parse light intensity value"))
+            .unwrap_or(1.0);
+        scale = value * read_color(&intensity_xml.attributes).unwrap_or(scale);
+    }
+
+    let illuminance = sky.sun_illuminance().mul_element_wise(scale);
+    let intensity = luminance(illuminance);
+    Light {
+        intensity,
+        color: if intensity > 0.0 { illuminance / intensity } else { Vector3::new(1.0, 1.0, 1.0) },
+        light_type: LightType::Directional { direction: -sky.sun, cos_radius: SUN_ANGULAR_RADIUS.cos() },
+        profile: None,
+    }
+}
+
+/* roughly the solid angle a spot light covers, counting the falloff as half */
+fn spot_solid_angle(cos_inner: f32, cos_outer: f32) -> f32 {
+    2.0 * consts::PI * (1.0 - 0.5 * (cos_inner + cos_outer))
+}
+
+/* reads an IES LM-63 photometric file. only type C photometry is supported, which is what
+ * nearly every architectural fixture comes with. */
+fn load_ies(filename: &str, down: Vector3<f32>, across: Vector3<f32>) -> IesProfile {
+    let mut f = File::open(filename).expect("ies file not found");
+    let mut contents = String::new();
+    f.read_to_string(&mut contents).expect("could not read ies file");
+
+    /* keywords come before the TILT line, numbers after it separated by spaces, commas or newlines */
+    let mut lines = contents.lines();
+    let tilt = lines.by_ref().map(|line| line.trim()).find(|line| line.starts_with("TILT="))
+        .expect("no TILT line found in ies file");
+    let rest: Vec<&str> = lines.collect();
+    let mut numbers = rest.iter()
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse::<f32>().expect("could not parse number in ies file"));
+    let mut next = || numbers.next().expect("ies file ended early");
+
+    if tilt == "TILT=INCLUDE" {
+        /* lamp to luminaire geometry, then pairs of tilt angles and factors, which don't matter
+         * for a light that isn't tilted */
+        next();
+        let pairs = next() as usize;
+        for _ in 0..2 * pairs {
+            next();
+        }
+    } else if tilt != "TILT=NONE" {
+        panic!("tilt data in a separate file isn't supported");
+    }
+
+    let _lamps = next();
+    let _lumens_per_lamp = next();
+    let multiplier = next();
+    let num_vertical = next() as usize;
+    let num_horizontal = next() as usize;
+    let photometric_type = next();
+    let _units = next();
+    let _width = next();
+    let _length = next();
+    let _height = next();
+    let ballast_factor = next();
+    let _future_use = next();
+    let _input_watts = next();
+
+    if photometric_type != 1.0 {
+        panic!("only type C ies files are supported");
+    }
+    if num_vertical == 0 || num_horizontal == 0 {
+        panic!("ies file has no angles");
+    }
+
+    let vertical_angles: Vec<f32> = (0..num_vertical).map(|_| next()).collect();
+    let horizontal_angles: Vec<f32> = (0..num_horizontal).map(|_| next()).collect();
+    let candela: Vec<f32> = (0..num_vertical * num_horizontal).map(|_| multiplier * ballast_factor * next()).collect();
+
+    IesProfile {
+        vertical_angles,
+        horizontal_angles,
+        candela,
+        down,
+        across,
+    }
+}
+
+/* quads, disks, spheres and point lights with a size */
+fn is_area_light(light_xml: &Element) -> bool {
+    match light_xml.attributes.get("type").map(|light_type| light_type.as_ref()) {
+        Some("quad") | Some("disk") | Some("sphere") => true,
+        /* sized point lights shine the same in every direction, so not with an ies profile */
+        Some("point") => point_light_size(light_xml) > 0.0 && light_xml.get_child("ies").is_none(),
+        _ => false,
+    }
+}
+
+fn point_light_size(light_xml: &Element) -> f32 {
+    light_xml.get_child("size")
+        .and_then(|size_xml| size_xml.attributes.get("value"))
+        .and_then(|size| size.parse().ok()).unwrap_or(0.0)
+}
+
+/* an area light is placed with the same scale, rotate and translate tags as an object. a quad
+ * is the plane object, facing up the z axis from -1 to 1 in x and y, and a disk the same with
+ * radius 1. a point light with a size is a sphere of that radius. */
+fn load_area_light(light_xml: &Element) -> (Node, Material) {
+    let light_type = light_xml.attributes.get("type").expect("no type for light");
+
+    let intensity_xml = light_xml.get_child("intensity").expect("no intensity given for light");
+    let intensity: f32 = intensity_xml.attributes.get("value").expect("no value for light intensity")
+        .parse().expect("could not parse light intensity value");
+    let color = read_color(&intensity_xml.attributes).unwrap_or(Vector3::new(1.0, 1.0, 1.0));
+    let units = intensity_xml.attributes.get("units").map(|units| units.as_ref());
+
+    let (geometry, transform) = match light_type.as_ref() {
+        "point" => {
+            let position = read_vector3(&light_xml.get_child("position")
+                .expect("no position given for positional light").attributes);
+            (Geometry::Sphere, Transform { transform: point_light_size(light_xml) * Matrix3::one(), translate: position })
+        },
+        "quad" => (Geometry::Plane, load_transform(light_xml)),
+        "disk" => (Geometry::Disk, load_transform(light_xml)),
+        _ => (Geometry::Sphere, load_transform(light_xml)),
+    };
+
+    /* what the light gives off in nits. quads and disks shine from both sides */
+    let x = transform.transform * Vector3::unit_x();
+    let y = transform.transform * Vector3::unit_y();
+    let area = match geometry {
+        Geometry::Plane => 2.0 * 4.0 * x.cross(y).magnitude(),
+        Geometry::Disk => 2.0 * consts::PI * x.cross(y).magnitude(),
+        _ => 4.0 * consts::PI * x.magnitude2(),
+    };
+    let radiance = match (units, light_type.as_ref()) {
+        (None, _) | (Some("nits"), _) => intensity,
+        (Some("candela"), "point") => intensity / (0.25 * area),
+        (Some("lumens"), _) => intensity / (consts::PI * area),
+        (Some("watts"), _) => LUMINOUS_EFFICACY * intensity / (consts::PI * area),
+        (Some(units), _) => panic!("units {} don't make sense for this type of light", units),
+    };
+
+    let name = format!("<{} light at {:?}>", light_type, transform.translate);
+    let node = Node {
+        object: Some(Object { geometry, material: name }),
+        transform,
+        children: Vec::new(),
+        name: "".to_string(),
+    };
+    (node, emissive_material(radiance * color))
+}
+
+fn emissive_material(emission: Color) -> Material {
+    let black = || constant_texture(Vector3::new(0.0, 0.0, 0.0));
+
+    Material {
+        diffuse: black(),
+        specular: black(),
+        glossiness: black(),
+        emission: constant_texture(emission),
+        reflection: black(),
+        reflection_glossiness: black(),
+        refraction: black(),
+        refraction_glossiness: black(),
+        refraction_index: RefractionIndex::Constant(1.0),
+        refraction_index_scale: constant_texture(Vector3::new(1.0, 1.0, 1.0)),
+        absorption: Vector3::new(0.0, 0.0, 0.0),
+        medium: None,
+        subsurface: false,
+        conductor: None,
+        clearcoat: None,
+        sheen: None,
+        normal_map: None,
+        bump: None,
+        opacity: None,
+    }
+}
+
+/* index defaults to 1.5, like varnish or lacquer */
+fn load_clearcoat(clearcoat_xml: &Element) -> Clearcoat {
+    let read_value = |name: &str, default: f32| clearcoat_xml.attributes.get(name)
+        .map(|value| value.parse().unwrap_or_else(|_| panic!("could not parse clearcoat {}", name)))
+        .unwrap_or(default);
+    Clearcoat {
+        index: read_value("index", 1.5),
+        glossiness: read_value("glossiness", 0.0),
+    }
+}
+
+/* a value, a color or both, and a roughness from 0 to 1 (default 0.5) */
+fn load_sheen(sheen_xml: &Element) -> Sheen {
+    let value: f32 = sheen_xml.attributes.get("value")
+        .map(|value| value.parse().expect("could not parse sheen value"))
+        .unwrap_or(1.0);
+    let color = value * read_color(&sheen_xml.attributes).unwrap_or(Vector3::new(1.0, 1.0, 1.0));
+    /* a sheen brighter than white would take more light than there is from the layers below */
+    let clamp = |c: f32| c.clamp(0.0, 1.0);
+    let color = Vector3::new(clamp(color.x), clamp(color.y), clamp(color.z));
+    let roughness = sheen_xml.attributes.get("roughness")
+        .map(|roughness| roughness.parse().expect("could not parse sheen roughness"))
+        .unwrap_or(0.5);
+    Sheen::new(color, roughness)
+}
+
+/* a constant index, or one that changes with wavelength: cauchy="a b" or sellmeier="b1 b2 b3 c1
+ * c2 c3", with b and c in square micrometers */
+fn load_refraction_index(refraction_xml: &Element) -> Option<RefractionIndex> {
+    let coefficients = |name: &str, count: usize| refraction_xml.attributes.get(name).map(|values| {
+        let values: Vec<f32> = values.split_whitespace()
+            .map(|value| value.parse().expect("could not parse refraction coefficient"))
+            .collect();
+        if values.len() != count {
+            panic!("{} takes {} coefficients", name, count);
+        }
+        values
+    });
+
+    refraction_xml.attributes.get("index")
+        .map(|index| RefractionIndex::Constant(index.parse().expect("could not parse refraction index")))
+        .or_else(|| coefficients("cauchy", 2).map(|values| RefractionIndex::Cauchy { a: values[0], b: values[1] }))
+        .or_else(|| coefficients("sellmeier", 6).map(|values| {
+            RefractionIndex::Sellmeier { b: [values[0], values[1], values[2]], c: [values[3], values[4], values[5]] }
+        }))
+}
+
+/* absorption and scattering are per meter, as a value, a color or both, and g is the phase
+ * function's asymmetry */
+fn load_medium(medium_xml: &Element) -> Medium {
+    let coefficient = |name: &str| medium_xml.get_child(name).map(|coefficient_xml| {
+        let value: f32 = coefficient_xml.attributes.get("value")
+            .map(|value| value.parse().expect("could not parse medium coefficient"))
+            .unwrap_or(1.0);
+        value * read_color(&coefficient_xml.attributes).unwrap_or(Vector3::new(1.0, 1.0, 1.0))
+    }).unwrap_or(Vector3::new(0.0, 0.0, 0.0));
+
+    Medium {
+        absorption: coefficient("absorption"),
+        scattering: coefficient("scattering"),
+        g: load_g(medium_xml),
+    }
+}
+
+/* the phase function's asymmetry, 0 (scattering evenly) if not given */
+fn load_g(xml: &Element) -> f32 {
+    let g: f32 = xml.get_child("g")
+        .and_then(|g_xml| g_xml.attributes.get("value"))
+        .map(|g| g.parse().expect("could not parse medium g"))
+        .unwrap_or(0.0);
+    if g <= -1.0 || g >= 1.0 {
+        panic!("medium g must be between -1 and 1");
+    }
+    g
+}
+
+/* tells density grids where their objects ended up in the world */
+fn place_volumes(node: &mut Node, parent_transform: &Matrix3<f32>, parent_translate: Vector3<f32>) {
+    let transform = parent_transform * node.transform.transform;
+    let translate = parent_transform * node.transform.translate + parent_translate;
+
+    if let Some(Object { geometry: Geometry::Volume(ref mut grid), .. }) = node.object {
+        grid.place(transform, translate);
+    }
+
+    for child in &mut node.children {
+        place_volumes(child, &transform, translate);
+    }
+}
+
+/* the box around every object in the scene, in world space */
+fn scene_bounds(nodes: &[Node]) -> BoundingBox {
+    fn add_node(node: &Node, parent_transform: &Matrix3<f32>, parent_translate: Vector3<f32>, bounds: &mut Option<BoundingBox>) {
+        let transform = parent_transform * node.transform.transform;
+        let translate = parent_transform * node.transform.translate + parent_translate;
+
+        if let Some(ref object) = node.object {
+            let local = object.geometry.bounding_box();
+            for i in 0..8 {
+                let corner = Vector3::new(
+                    if i & 1 == 0 { local.p1.x } else { local.p2.x },
+                    if i & 2 == 0 { local.p1.y } else { local.p2.y },
+                    if i & 4 == 0 { local.p1.z } else { local.p2.z });
+                let p = transform * corner + translate;
+                let corner_box = BoundingBox::new(p.x, p.y, p.z, p.x, p.y, p.z);
+                match *bounds {
+                    Some(ref mut bounds) => bounds.union(&corner_box),
+                    None => *bounds = Some(corner_box),
+                }
+            }
+        }
+
+        for child in &node.children {
+            add_node(child, &transform, translate, bounds);
+        }
+    }
+
+    let mut bounds = None;
+    for node in nodes {
+        add_node(node, &Matrix3::one(), Vector3::new(0.0, 0.0, 0.0), &mut bounds);
     }
+    bounds.unwrap_or(BoundingBox::new(0.0, 0.0, 0.0, 0.0, 0.0, 0.0))
 }
 
 fn load_camera(camera_xml: &Element) -> Camera {
@@ -245,9 +777,48 @@ fn load_camera(camera_xml: &Element) -> Camera {
     camera.dir = (read_vector3_default(&camera_xml.get_child("target").expect("no <target> tag found in <camera>").attributes, camera.pos + camera.dir)
         - camera.pos).normalize();
     camera.up = read_vector3_default(&camera_xml.get_child("up").expect("no <up> tag found in <camera>").attributes, camera.up);
-    camera.fov = camera_xml.get_child("fov").expect("no <fov> tag found in <camera>")
-        .attributes.get("value").expect("no value attribute found on <fov> tag")
-        .parse().expect("could not parse camera fov");
+
+    camera.projection = camera_xml.attributes.get("type")
+        .map(|projection| Projection::from_name(projection).expect("unknown camera type"))
+        .unwrap_or(camera.projection);
+    if let Projection::Orthographic { ref mut size } = camera.projection {
+        *size = camera_xml.get_child("size")
+            .and_then(|size_xml| size_xml.attributes.get("value"))
+            .map(|size| size.parse().expect("could not parse orthographic camera size"))
+            .unwrap_or(0.0);
+    }
+
+    /* a focal length in millimetres gives the field of view on a 36x24mm sensor */
+    let focal_length: Option<f32> = camera_xml.get_child("dof")
+        .and_then(|dof_xml| dof_xml.attributes.get("focallength"))
+        .map(|focal_length| focal_length.parse().expect("could not parse focal length"));
+    if let Some(focal_length) = focal_length {
+        camera.fov = 2.0 * (SENSOR_HEIGHT / (2.0 * focal_length)).atan() * 180.0 / consts::PI;
+    }
+
+    if let Projection::Realistic(ref mut system) = camera.projection {
+        let lens_xml = camera_xml.get_child("lens").expect("no <lens> tag found in realistic <camera>");
+        *system = load_lens(lens_xml.attributes.get("file").expect("no file attribute found on <lens> tag"));
+        if let Some(stop) = lens_xml.attributes.get("aperture") {
+            /* stop the lens down (or open it up) to a diameter in millimetres */
+            let diameter: f32 = stop.parse().expect("could not parse lens aperture");
+            for element in system.elements.iter_mut().filter(|element| element.radius == 0.0) {
+                element.aperture_radius = diameter / 2000.0;
+            }
+        }
+    }
+
+    let fov_xml = camera_xml.get_child("fov");
+    if fov_xml.is_none() && focal_length.is_none() && camera.projection == Projection::Perspective {
+        panic!("no <fov> tag found in <camera>");
+    }
+    if camera.projection == Projection::Fisheye {
+        camera.fov = 180.0;
+    }
+    camera.fov = fov_xml
+        .map(|fov_xml| fov_xml.attributes.get("value").expect("no value attribute found on <fov> tag")
+            .parse().expect("could not parse camera fov"))
+        .unwrap_or(camera.fov);
     camera.img_width = camera_xml.get_child("width").expect("no <width> tag found in <camera>")
         .attributes.get("value").expect("no value attribute found on <width> tag")
         .parse().expect("could not parse camera width");
@@ -257,16 +828,162 @@ fn load_camera(camera_xml: &Element) -> Camera {
     camera.focaldist = camera_xml.get_child("focaldist")
         .and_then(|focaldist_xml| focaldist_xml.attributes.get("value"))
         .and_then(|focaldist| focaldist.parse().ok()).unwrap_or(camera.focaldist);
-    camera.dof = camera_xml.get_child("dof")
-        .and_then(|dof_xml| dof_xml.attributes.get("value"))
-        .and_then(|dof| dof.parse().ok()).unwrap_or(camera.dof);
+    if let Some(dof_xml) = camera_xml.get_child("dof") {
+        if let Some(fstop) = dof_xml.attributes.get("fstop") {
+            /* the aperture is focal length / f-number across, in a scene measured in meters */
+            let fstop: f32 = fstop.parse().expect("could not parse f-stop");
+            let focal_length = focal_length.expect("no focallength attribute found next to fstop on <dof> tag");
+            camera.dof = focal_length / 1000.0 / (2.0 * fstop);
+        } else {
+            camera.dof = dof_xml.attributes.get("value")
+                .and_then(|dof| dof.parse().ok()).unwrap_or(camera.dof);
+        }
+    }
+    if let Some(exposure_xml) = camera_xml.get_child("exposure") {
+        let shutter = read_fraction(exposure_xml.attributes.get("shutter").expect("no shutter attribute found on <exposure> tag"))
+            .expect("could not parse shutter time");
+        let iso = exposure_xml.attributes.get("iso").expect("no iso attribute found on <exposure> tag")
+            .parse().expect("could not parse iso");
+        /* the f-stop of the depth of field, if it has one */
+        let fstop = exposure_xml.attributes.get("fstop")
+            .or_else(|| camera_xml.get_child("dof").and_then(|dof_xml| dof_xml.attributes.get("fstop")))
+            .expect("no fstop attribute found on <exposure> tag")
+            .parse().expect("could not parse f-stop");
+        camera.exposure = exposure(shutter, iso, fstop);
+    }
+    if let Some(aperture_xml) = camera_xml.get_child("aperture") {
+        camera.aperture = load_aperture(aperture_xml);
+    }
+
+    if let Projection::Realistic(ref mut system) = camera.projection {
+        let width = camera.img_width as f32;
+        let height = camera.img_height as f32;
+        system.film_height = SENSOR_HEIGHT / 1000.0;
+        system.film_width = system.film_height * width / height;
+        system.focus(camera.focaldist);
+    }
 
     /* make sure camera.up is orthogonal to camera.dir */
-    camera.up = (camera.dir.cross(camera.up)).cross(camera.dir);
+    camera.up = (camera.dir.cross(camera.up)).cross(camera.dir).normalize();
 
     camera
 }
 
+fn load_aperture(aperture_xml: &Element) -> Aperture {
+    match aperture_xml.attributes.get("shape").map(|shape| shape.as_ref()).unwrap_or("disk") {
+        "disk" => Aperture::Disk,
+        "polygon" => {
+            let blades = aperture_xml.attributes.get("blades").expect("no blades attribute found on polygon <aperture> tag")
+                .parse().expect("could not parse aperture blade count");
+            if blades < 3 {
+                panic!("a polygonal aperture needs at least 3 blades");
+            }
+            let rotation = aperture_xml.attributes.get("rotation")
+                .map(|rotation| rotation.parse().expect("could not parse aperture rotation"))
+                .unwrap_or(0.0);
+            Aperture::Polygon { blades, rotation }
+        },
+        "image" => {
+            let filename = aperture_xml.attributes.get("file").expect("no file attribute found on image <aperture> tag");
+            Aperture::Mask(load_aperture_mask(filename))
+        },
+        _ => panic!("unknown aperture shape"),
+    }
+}
+
+fn load_aperture_mask(filename: &str) -> Distribution2D {
+    let (pixels, width, height) = match load_img(filename) {
+        TextureData::Image { pixels, width, height, .. } => (pixels, width, height),
+        _ => unreachable!(),
+    };
+
+    /* the first channel of whatever the image holds, which is the gray of a grayscale image */
+    let weights: Vec<f32> = pixels.chunks(4).map(|pixel| pixel[0] as f32 / 65535.0).collect();
+    Distribution2D::new(width, height, &weights).expect("aperture mask is completely black")
+}
+
+/* a lens prescription: one line per surface from the front of the lens to the back, giving
+ * its radius of curvature, the distance to the next surface, the index of refraction behind it
+ * and its aperture diameter, all in millimetres. a radius of 0 is the aperture stop and an
+ * index of 0 means air. # starts a comment. */
+fn load_lens(filename: &str) -> LensSystem {
+    let mut f = File::open(filename).expect("lens file not found");
+    let mut contents = String::new();
+    f.read_to_string(&mut contents).expect("could not read lens file");
+
+    let mut elements = Vec::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let values: Vec<f32> = line.split_whitespace()
+            .map(|value| value.parse().expect("could not parse lens element"))
+            .collect();
+        if values.len() != 4 {
+            panic!("lens elements need a radius, thickness, index of refraction and aperture");
+        }
+
+        elements.push(LensElement {
+            radius: values[0] / 1000.0,
+            thickness: values[1] / 1000.0,
+            ior: if values[2] == 0.0 { 1.0 } else { values[2] },
+            aperture_radius: values[3] / 2000.0,
+        });
+    }
+
+    if elements.is_empty() {
+        panic!("lens file has no elements");
+    }
+
+    LensSystem { elements, film_width: 0.0, film_height: 0.0 }
+}
+
+fn load_sampling(sampling_xml: &Element) -> Sampling {
+    let mut sampling: Sampling = Default::default();
+
+    sampling.initial_samples = sampling_xml.get_child("initial")
+        .and_then(|initial_xml| initial_xml.attributes.get("value"))
+        .map(|initial| initial.parse().expect("could not parse initial sample count"))
+        .unwrap_or(sampling.initial_samples);
+    sampling.max_samples = sampling_xml.get_child("max")
+        .and_then(|max_xml| max_xml.attributes.get("value"))
+        .map(|max| max.parse().expect("could not parse max sample count"))
+        .unwrap_or(sampling.max_samples);
+    if sampling.initial_samples == 0 {
+        panic!("initial sample count must be positive");
+    }
+    if sampling.initial_samples > sampling.max_samples {
+        panic!("initial sample count must not exceed the max sample count");
+    }
+    sampling.threshold = sampling_xml.get_child("threshold")
+        .and_then(|threshold_xml| threshold_xml.attributes.get("value"))
+        .map(|threshold| threshold.parse().expect("could not parse sampling threshold"))
+        .unwrap_or(sampling.threshold);
+    sampling.sampler = sampling_xml.get_child("sampler")
+        .and_then(|sampler_xml| sampler_xml.attributes.get("value"))
+        .map(|sampler| SamplerType::from_name(sampler).expect("unknown sampler type"))
+        .unwrap_or(sampling.sampler);
+    sampling.seed = sampling_xml.get_child("seed")
+        .and_then(|seed_xml| seed_xml.attributes.get("value"))
+        .map(|seed| seed.parse().expect("could not parse seed"))
+        .unwrap_or(sampling.seed);
+    sampling.tile_size = sampling_xml.get_child("tilesize")
+        .and_then(|tile_size_xml| tile_size_xml.attributes.get("value"))
+        .map(|tile_size| tile_size.parse().expect("could not parse tile size"))
+        .unwrap_or(sampling.tile_size);
+    if sampling.tile_size == 0 {
+        panic!("tile size must be positive");
+    }
+    sampling.tile_order = sampling_xml.get_child("tileorder")
+        .and_then(|tile_order_xml| tile_order_xml.attributes.get("value"))
+        .map(|tile_order| TileOrder::from_name(tile_order).expect("unknown tile order"))
+        .unwrap_or(sampling.tile_order);
+
+    sampling
+}
+
 fn load_obj(filename: &str) -> Geometry {
     let mut f = File::open(filename).expect("file not found");
     let mut contents = String::new();
@@ -309,16 +1026,20 @@ fn load_obj(filename: &str) -> Geometry {
                 let bounding_box = BoundingBox { p1: p1, p2: p2 };
                 let bvh = Mesh::build_bvh(&vertices, &triangles, bounding_box);
 
-                Geometry::Mesh(Mesh {
+                let mut mesh = Mesh {
                     vertices: vertices,
                     triangles: triangles,
                     normals: object.normals.iter().map(|v| Vector3::new(v.x as f32, v.y as f32, v.z as f32)).collect(),
                     normal_triangles: normal_triangles,
                     texture_vertices: object.tex_vertices.iter().map(|v| Vector3::new(v.u as f32, v.v as f32, v.w as f32)).collect(),
                     texture_triangles: texture_triangles,
+                    tangents: Vec::new(),
+                    bitangents: Vec::new(),
                     bounding_box: bounding_box,
                     bvh: bvh,
-                })
+                };
+                mesh.compute_tangents();
+                Geometry::Mesh(Box::new(mesh))
             }
         },
         Err(parse_error) => {
@@ -327,6 +1048,27 @@ fn load_obj(filename: &str) -> Geometry {
     }
 }
 
+fn constant_texture(color: Color) -> Texture {
+    Texture {
+        data: TextureData::Blank,
+        color,
+        transform: Transform::default(),
+    }
+}
+
+/* a number that can also come from a texture: a <name> child holding a texture or value, or a
+ * name attribute on xml. a texture without a value is taken as it is */
+fn load_parameter(xml: &Element, name: &str, default: f32) -> Texture {
+    if let Some(parameter_xml) = xml.get_child(name) {
+        let default = if parameter_xml.attributes.contains_key("texture") { 1.0 } else { default };
+        return load_texture(parameter_xml, Vector3::new(default, default, default));
+    }
+    let value = xml.attributes.get(name)
+        .map(|value| value.parse().unwrap_or_else(|_| panic!("could not parse {}", name)))
+        .unwrap_or(default);
+    constant_texture(Vector3::new(value, value, value))
+}
+
 fn load_texture(texture_xml: &Element, default_color: Color) -> Texture {
     let maybe_value = texture_xml.attributes.get("value");
     let maybe_color = read_color(&texture_xml.attributes);
@@ -348,6 +1090,23 @@ fn load_texture(texture_xml: &Element, default_color: Color) -> Texture {
             }
 
             TextureData::Checkerboard { color1, color2 }
+        } else if texture == "sky" {
+            /* sun points towards the sun, turbidity runs from 2 for clear air to 10 for haze,
+             * and albedo is the reflectance of the ground below the horizon */
+            let sun = read_vector3(&texture_xml.get_child("sun").expect("no sun direction given for sky").attributes);
+            let read_value = |name: &str, default: f32, error: &str| texture_xml.get_child(name)
+                .and_then(|value_xml| value_xml.attributes.get("value"))
+                .map(|value| value.parse().expect(error))
+                .unwrap_or(default);
+            let turbidity = read_value("turbidity", 3.0, "could not parse sky turbidity");
+            if !(1.7..=10.0).contains(&turbidity) {
+                panic!("sky turbidity must be between 1.7 and 10");
+            }
+            TextureData::Sky(Sky::new(sun, turbidity, read_value("albedo", 0.2, "could not parse sky albedo")))
+        } else if texture.ends_with(".hdr") || texture.ends_with(".exr") {
+            let image = load_hdr_image(texture);
+            let mipmap = build_mipmap(image.width, image.height, |i| Vector3::new(image.pixels[3 * i], image.pixels[3 * i + 1], image.pixels[3 * i + 2]));
+            TextureData::HdrImage { pixels: image.pixels, width: image.width, height: image.height, mipmap }
         } else {
             load_img(texture)
         }
@@ -422,6 +1181,16 @@ fn read_vector3_default(attrs: &HashMap<String, String>, default: Vector3<f32>)
     )
 }
 
+/* a number, or a fraction like 1/125 */
+fn read_fraction(value: &str) -> Option<f32> {
+    let mut parts = value.splitn(2, '/');
+    let numerator: f32 = parts.next()?.trim().parse().ok()?;
+    match parts.next() {
+        Some(denominator) => Some(numerator / denominator.trim().parse::<f32>().ok()?),
+        None => Some(numerator),
+    }
+}
+
 fn read_color(attrs: &HashMap<String, String>) -> Option<Color> {
     let r = attrs.get("r").and_then(|s| s.parse().ok());
     let g = attrs.get("g").and_then(|s| s.parse().ok());
@@ -435,9 +1204,178 @@ fn read_color(attrs: &HashMap<String, String>) -> Option<Color> {
 }
 
 fn load_img(filename: &str) -> TextureData {
-    let decoder = png::Decoder::new(File::open(filename).unwrap());
-    let (info, mut reader) = decoder.read_info().unwrap();
-    let mut buf = vec![0; info.buffer_size()];
-    reader.next_frame(&mut buf).unwrap();
-    TextureData::Image { pixels: buf, width: info.width as usize, height: info.height as usize }
+    let mut decoder = png::Decoder::new(File::open(filename).unwrap_or_else(|_| panic!("texture {} not found", filename)));
+    /* the samples as they are in the file, spread out into rgba by decode_png */
+    decoder.set(png::TRANSFORM_IDENTITY);
+    let (output_info, mut reader) = decoder.read_info().unwrap_or_else(|err| panic!("could not read {}: {:?}", filename, err));
+    let mut buf = vec![0; output_info.buffer_size()];
+    if reader.info().interlaced {
+        deinterlace(&mut reader, &mut buf).unwrap_or_else(|err| panic!("could not read {}: {}", filename, err));
+    } else {
+        reader.next_frame(&mut buf).unwrap_or_else(|err| panic!("could not read {}: {:?}", filename, err));
+    }
+
+    decode_png(&buf, reader.info()).unwrap_or_else(|err| panic!("could not read {}: {}", filename, err))
+}
+
+/* puts the seven passes of an interlaced png back together into rows. the png crate does this
+ * a byte a sample, which scrambles 16 bit images, so it's done here a whole pixel at a time */
+fn deinterlace<R: Read>(reader: &mut png::Reader<R>, buf: &mut [u8]) -> Result<(), String> {
+    let (width, bits, samples) = {
+        let info = reader.info();
+        (info.width as usize, info.bit_depth as usize, info.color_type.samples())
+    };
+    if bits < 8 {
+        return Err(format!("interlaced images with {} bit samples aren't supported", bits));
+    }
+    let pixel_size = samples * bits / 8;
+    let row_size = width * pixel_size;
+
+    while let Some((row, adam7)) = reader.next_interlaced_row().map_err(|err| format!("{:?}", err))? {
+        let (pass, line, _) = adam7.ok_or("interlaced row without a pass")?;
+        /* where each pass starts and how far apart its pixels are, in x and y */
+        let (x0, y0, dx, dy) = match pass {
+            1 => (0, 0, 8, 8),
+            2 => (4, 0, 8, 8),
+            3 => (0, 4, 4, 8),
+            4 => (2, 0, 4, 4),
+            5 => (0, 2, 2, 4),
+            6 => (1, 0, 2, 2),
+            _ => (0, 1, 1, 2),
+        };
+        let y = y0 + dy * line as usize;
+        for (j, pixel) in row.chunks(pixel_size).enumerate() {
+            let x = x0 + dx * j;
+            if x < width && (y + 1) * row_size <= buf.len() {
+                let start = y * row_size + x * pixel_size;
+                buf[start..start + pixel_size].copy_from_slice(pixel);
+            }
+        }
+    }
+    Ok(())
+}
+
+/* any kind of png as 16 bit rgba: gray goes to red, green and blue, palette indices are looked
+ * up, samples of fewer bits are scaled up and alpha comes from an alpha channel or the color the
+ * file marks transparent, and is opaque without either */
+fn decode_png(data: &[u8], info: &png::Info) -> Result<TextureData, String> {
+    let (width, height) = (info.width as usize, info.height as usize);
+    let bits = info.bit_depth as usize;
+    let samples = info.color_type.samples();
+    let row_size = (width * samples * bits).div_ceil(8);
+    if data.len() < row_size * height {
+        return Err("image data ended early".to_string());
+    }
+
+    /* a sample as stored, packed several to a byte below 8 bits and big endian at 16 */
+    let sample = |row: &[u8], i: usize| -> u16 {
+        match bits {
+            16 => (row[2 * i] as u16) << 8 | row[2 * i + 1] as u16,
+            8 => row[i] as u16,
+            _ => {
+                let per_byte = 8 / bits;
+                let shift = 8 - bits * (i % per_byte + 1);
+                (row[i / per_byte] >> shift) as u16 & ((1 << bits) - 1)
+            },
+        }
+    };
+    let scale = |value: u16| if bits == 16 { value } else { value * (65535 / ((1 << bits) - 1)) };
+    let opaque = 65535;
+    /* the transparent color, which the decoder leaves as a byte a sample below 16 bits */
+    let transparent = |raw: &[u16]| info.trns.as_ref().is_some_and(|trns| {
+        let stored = |c: usize| if bits == 16 { (trns[2 * c] as u16) << 8 | trns[2 * c + 1] as u16 } else { trns[c] as u16 };
+        trns.len() >= raw.len() * if bits == 16 { 2 } else { 1 } && raw.iter().enumerate().all(|(c, &value)| stored(c) == value)
+    });
+
+    let mut pixels = Vec::with_capacity(4 * width * height);
+    for y in 0..height {
+        let row = &data[y * row_size..(y + 1) * row_size];
+        for x in 0..width {
+            let mut raw = [0; 4];
+            for (c, value) in raw.iter_mut().enumerate().take(samples) {
+                *value = sample(row, x * samples + c);
+            }
+            let rgba = match info.color_type {
+                png::ColorType::Grayscale => {
+                    let gray = scale(raw[0]);
+                    [gray, gray, gray, if transparent(&raw[..1]) { 0 } else { opaque }]
+                },
+                png::ColorType::GrayscaleAlpha => {
+                    let gray = scale(raw[0]);
+                    [gray, gray, gray, scale(raw[1])]
+                },
+                png::ColorType::RGB => {
+                    [scale(raw[0]), scale(raw[1]), scale(raw[2]), if transparent(&raw[..3]) { 0 } else { opaque }]
+                },
+                png::ColorType::RGBA => [scale(raw[0]), scale(raw[1]), scale(raw[2]), scale(raw[3])],
+                png::ColorType::Indexed => {
+                    /* the palette holds 8 bit rgb, and the transparency chunk an alpha for each entry */
+                    let i = raw[0] as usize;
+                    let palette = info.palette.as_ref().ok_or("palette missing")?;
+                    if 3 * i + 2 >= palette.len() {
+                        return Err(format!("palette index {} out of range", i));
+                    }
+                    let alpha = info.trns.as_ref().and_then(|trns| trns.get(i)).map_or(opaque, |&alpha| alpha as u16 * 257);
+                    [palette[3 * i] as u16 * 257, palette[3 * i + 1] as u16 * 257, palette[3 * i + 2] as u16 * 257, alpha]
+                },
+            };
+            pixels.extend_from_slice(&rgba);
+        }
+    }
+
+    let alpha = match info.color_type {
+        png::ColorType::GrayscaleAlpha | png::ColorType::RGBA => true,
+        _ => info.trns.is_some(),
+    };
+    let mipmap = build_mipmap(width, height, |i| u16_array_as_color(&pixels[4 * i..4 * i + 3]));
+    Ok(TextureData::Image { pixels, width, height, alpha, mipmap })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(width: u32, height: u32, bit_depth: png::BitDepth, color_type: png::ColorType) -> png::Info {
+        png::Info { width, height, bit_depth, color_type, ..png::Info::default() }
+    }
+
+    fn rgba(texture: TextureData) -> (Vec<u16>, bool) {
+        match texture {
+            TextureData::Image { pixels, alpha, .. } => (pixels, alpha),
+            _ => panic!("not an image"),
+        }
+    }
+
+    #[test]
+    fn gray_1_bit() {
+        /* ten pixels packed into two bytes, the last six bits padding */
+        let (pixels, alpha) = rgba(decode_png(&[0b1010_0000, 0b1100_0000], &info(10, 1, png::BitDepth::One, png::ColorType::Grayscale)).unwrap());
+        let grays: Vec<u16> = pixels.chunks(4).map(|pixel| pixel[0]).collect();
+        assert_eq!(grays, vec![65535, 0, 65535, 0, 0, 0, 0, 0, 65535, 65535]);
+        assert!(pixels.chunks(4).all(|pixel| pixel[1] == pixel[0] && pixel[2] == pixel[0] && pixel[3] == 65535));
+        assert!(!alpha);
+    }
+
+    #[test]
+    fn palette_with_transparency() {
+        /* two 4 bit indices in one byte, and an alpha for the first palette entry only */
+        let mut info = info(2, 1, png::BitDepth::Four, png::ColorType::Indexed);
+        info.palette = Some(vec![255, 0, 0, 0, 0, 255]);
+        info.trns = Some(vec![128]);
+        let (pixels, alpha) = rgba(decode_png(&[0x01], &info).unwrap());
+        assert_eq!(pixels, vec![65535, 0, 0, 128 * 257, 0, 0, 65535, 65535]);
+        assert!(alpha);
+
+        assert!(decode_png(&[0x21], &info).is_err());
+    }
+
+    #[test]
+    fn rgba_16_bit() {
+        let data = [0x12, 0x34, 0xff, 0xff, 0x00, 0x01, 0x80, 0x00];
+        let (pixels, alpha) = rgba(decode_png(&data, &info(1, 1, png::BitDepth::Sixteen, png::ColorType::RGBA)).unwrap());
+        assert_eq!(pixels, vec![0x1234, 0xffff, 0x0001, 0x8000]);
+        assert!(alpha);
+
+        assert!(decode_png(&data[..6], &info(1, 1, png::BitDepth::Sixteen, png::ColorType::RGBA)).is_err());
+    }
 }
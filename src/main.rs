@@ -2,119 +2,141 @@ extern crate png;
 extern crate cgmath;
 extern crate rayon;
 extern crate rand;
+extern crate ctrlc;
 
 mod load;
 mod scene;
+mod camera;
+mod light;
+mod hdr;
+mod sky;
+mod medium;
+mod grid;
+mod spectrum;
+mod fresnel;
+mod layers;
 mod geometry;
 mod bvh;
+mod film;
+mod options;
+mod sampler;
+mod render;
+mod distributed;
 
 use load::*;
 use scene::*;
-
-use std::env;
+use film::*;
+use options::*;
+use render::*;
+use sampler::*;
+use distributed::*;
 
 use std::f32;
-use std::f32::consts;
-use self::cgmath::{Vector3, InnerSpace};
+use std::process;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use self::cgmath::Vector3;
 
 use std::path::Path;
 use std::fs::File;
 use std::io::BufWriter;
 use png::HasParameters;
 
-use rayon::prelude::*;
-
-const R_THRESHOLD: f32 = 0.4;
-const G_THRESHOLD: f32 = 0.3;
-const B_THRESHOLD: f32 = 0.6;
-
-const INITIAL_SAMPLES: i32 = 128;
-const MAX_SAMPLES: i32 = 512;
-
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        panic!("please provide a scene description file and an image output file");
-    }
-    let filename = &args[1];
+    let options = parse_args();
 
-    let (scene, camera) = load_scene(filename);
-
-    let height = ((camera.fov / 2.0) * (2.0 * consts::PI / 360.0)).tan() * 2.0 * camera.focaldist;
-    let width = height * (camera.img_width as f32) / (camera.img_height as f32);
-    let pixel_height = height / (camera.img_height as f32);
-    let pixel_width = width / (camera.img_width as f32);
-
-    let right = camera.dir.cross(camera.up);
-
-    /* top-middle of the screen */
-    let b = camera.pos + camera.focaldist * camera.dir + (height / 2.0) * camera.up;
-    /* top-left corner of the screen */
-    let a = b - (width / 2.0) * right;
+    let serving = options.serve.is_some();
+    ctrlc::set_handler(move || {
+        /* a second ctrl-c gives up on writing the checkpoint, and workers have nothing to write */
+        if INTERRUPTED.swap(true, Ordering::SeqCst) || serving {
+            process::exit(130);
+        }
+        eprintln!("\ninterrupted, writing checkpoint...");
+    }).expect("could not install ctrl-c handler");
+
+    let (scene, camera, mut sampling) = load_scene(&options.scene);
+    sampling.initial_samples = options.initial_samples.unwrap_or(sampling.initial_samples);
+    sampling.max_samples = options.max_samples.unwrap_or(sampling.max_samples);
+    if sampling.initial_samples > sampling.max_samples {
+        panic!("initial sample count must not exceed the max sample count");
+    }
+    sampling.threshold = options.threshold.unwrap_or(sampling.threshold);
+    sampling.sampler = options.sampler.unwrap_or(sampling.sampler);
+    sampling.seed = options.seed.unwrap_or(sampling.seed);
+    sampling.tile_size = options.tile_size.unwrap_or(sampling.tile_size);
+    sampling.tile_order = options.tile_order.unwrap_or(sampling.tile_order);
+
+    let sample_pixel = |x: u32, y: u32, sampler: &mut dyn Sampler| -> Color {
+        let (x_offset, y_offset) = sampler.next_2d();
+        let lens = sampler.next_2d();
+
+        let film_x = x as f32 + x_offset;
+        let film_y = y as f32 + y_offset;
+        match camera.generate_ray(film_x, film_y, lens) {
+            Some((eye, dir)) => {
+                let differential = camera.generate_differential(film_x, film_y, lens, eye, dir);
+                camera.exposure * scene.sample(eye, dir, film_x / camera.img_width as f32, film_y / camera.img_height as f32, differential, sampler)
+            },
+            None => Vector3::new(0.0, 0.0, 0.0),
+        }
+    };
 
-    let mut img: Vec<u8> = vec![0; (camera.img_width * camera.img_height * 4) as usize];
+    if let Some(ref address) = options.serve {
+        serve(address, camera.img_width, camera.img_height, &sample_pixel);
+        return;
+    }
 
-    img.par_chunks_mut(4).enumerate().for_each(|(i, pixel)| {
-        let x = (i as u32) % camera.img_width;
-        let y = (i as u32) / camera.img_width;
-        if x == 0 {
-            println!("{}", y);
+    let mut film = if options.resume {
+        let film = Film::load_checkpoint(&options.checkpoint).expect("could not load checkpoint");
+        if film.width != camera.img_width || film.height != camera.img_height {
+            panic!("checkpoint is {}x{} but the scene is {}x{}", film.width, film.height, camera.img_width, camera.img_height);
         }
-
-        /* top-left corner of the current pixel */
-        let top_left = a + x as f32 * pixel_width * right - y as f32 * pixel_height * camera.up;
-
-        let mut samples = Vec::new();
-        let mut num_samples = INITIAL_SAMPLES;
-        // let mut iters = 0;
-
-        loop {
-            let mut new_samples: Vec<Color> = ((samples.len() as i32 + 1)..(num_samples + 1)).into_par_iter().map(|i| {
-                let x_offset = halton(i, 2);
-                let y_offset = halton(i, 3);
-
-                let r1: f32 = rand::random();
-                let r2: f32 = rand::random();
-                let eye_x_offset: f32 = 2.0 * r1 - 1.0;//2.0 * halton(i, 5) - 1.0;
-                let eye_y_offset: f32 = 2.0 * r2 - 1.0;//2.0 * halton(i, 7) - 1.0;
-
-                let p: Vector3<f32> = top_left + x_offset * pixel_width * right - y_offset * pixel_height * camera.up;
-                let eye: Vector3<f32> = camera.pos + eye_x_offset * camera.dof * right + eye_y_offset * camera.dof * camera.up;
-
-                let dir = (p - eye).normalize();
-                scene.sample(eye, dir, (x as f32 + x_offset) / camera.img_width as f32, (y as f32 + y_offset) / camera.img_height as f32)
-            }).collect();
-
-            samples.append(&mut new_samples);
-
-            let mut r_min = f32::INFINITY; let mut r_max = 0.0;
-            let mut g_min = f32::INFINITY; let mut g_max = 0.0;
-            let mut b_min = f32::INFINITY; let mut b_max = 0.0;
-            for sample in &samples {
-                if sample.x < r_min { r_min = sample.x; }
-                if sample.x > r_max { r_max = sample.x; }
-                if sample.y < g_min { g_min = sample.y; }
-                if sample.y > g_max { g_max = sample.y; }
-                if sample.z < b_min { b_min = sample.z; }
-                if sample.z > b_max { b_max = sample.z; }
-            }
-            if num_samples < MAX_SAMPLES && ((r_max - r_min) / (r_min + r_max) > R_THRESHOLD || (g_max - g_min) / (g_min + g_max) > G_THRESHOLD || (b_max - b_min) / (b_min + b_max) > B_THRESHOLD) {
-                num_samples *= 2;
-                // iters += 1;
-            } else {
-                break;
-            }
+        film
+    } else {
+        Film::new(camera.img_width, camera.img_height)
+    };
+
+    let checkpoint_interval = Duration::from_secs(options.checkpoint_interval);
+    let mut last_checkpoint = Instant::now();
+    let after_tile = |film: &Film| {
+        if options.checkpoint_interval > 0 && last_checkpoint.elapsed() >= checkpoint_interval {
+            film.save_checkpoint(&options.checkpoint).expect("could not write checkpoint");
+            last_checkpoint = Instant::now();
         }
+    };
+
+    let region = options.crop.map(|crop| crop.rect(camera.img_width, camera.img_height))
+        .unwrap_or(Rect { x: 0, y: 0, width: camera.img_width, height: camera.img_height });
+    let result = if options.workers.is_empty() {
+        render(&mut film, region, &sampling, &sample_pixel, after_tile);
+        Ok(())
+    } else {
+        /* a worker rendering a heavily sampled tile can take a while, so this can be turned off */
+        let timeout = if options.worker_timeout > 0 { Some(Duration::from_secs(options.worker_timeout)) } else { None };
+        render_distributed(&mut film, region, &sampling, &options.workers, timeout, after_tile)
+    };
+
+    film.save_checkpoint(&options.checkpoint).expect("could not write checkpoint");
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        println!("checkpoint written to {}, continue with --resume", options.checkpoint);
+        process::exit(1);
+    }
+    if interrupted() {
+        println!("checkpoint written to {}, continue with --resume", options.checkpoint);
+        process::exit(130);
+    }
 
-        let total: Color = samples.iter().sum();
-        let color: [u8; 4] = color_as_u8_array(total / num_samples as f32);
-        // let brightness = 255.0 * iters as f32 / 2.0 as f32;
-        // let color: [u8; 4] = color_as_u8_array(Vector3::new(brightness, brightness, brightness));
-
-        pixel.copy_from_slice(&color);
-    });
+    /* pixels outside the region may still hold samples from a resumed checkpoint */
+    let film = match options.crop_output {
+        CropOutput::Cropped => film.crop(region),
+        CropOutput::Full => film.mask(region),
+    };
 
-    save_img(&args[2], camera.img_width, camera.img_height, &img);
+    save_img(&options.output, film.width, film.height, &film.to_rgba8());
+    if let Some(ref heatmap) = options.heatmap {
+        save_img(heatmap, film.width, film.height, &film.heatmap_rgba8(sampling.max_samples));
+    }
 }
 
 fn save_img(filename: &str, width: u32, height: u32, img: &[u8]) {
@@ -0,0 +1,158 @@
+extern crate cgmath;
+
+use scene::*;
+use sampler::*;
+use light::orthonormal_basis;
+use grid::DensityGrid;
+use spectrum::Channels;
+
+use std::f32::consts;
+use self::cgmath::{Vector3, InnerSpace, ElementWise};
+
+/* a participating medium: absorption and scattering coefficients per meter, and the
+ * Henyey-Greenstein asymmetry g, from -1 scattering straight back to 1 straight on */
+#[derive(Debug, Clone)]
+pub struct Medium {
+    pub absorption: Color,
+    pub scattering: Color,
+    pub g: f32,
+}
+
+/* what happens to a ray crossing a stretch of medium, with the weight it carries on */
+pub enum MediumEvent {
+    Scatter { distance: f32, weight: Color },
+    Absorb,
+    Pass { weight: Color },
+}
+
+/* the stretch of the ray from pos along dir between the distances start and end that goes
+ * through a medium, scaled by a density grid if there is one, with coefficients for the
+ * channels the ray carries */
+pub struct MediumSegment<'a> {
+    pub medium: &'a Medium,
+    pub grid: Option<&'a DensityGrid>,
+    pub pos: Vector3<f32>,
+    pub dir: Vector3<f32>,
+    pub start: f32,
+    pub end: f32,
+    pub channels: Channels,
+}
+
+impl Medium {
+    /* the fraction of interactions that scatter, for russian roulette */
+    pub fn albedo(&self) -> f32 {
+        let extinction = average(self.absorption + self.scattering);
+        if extinction > 0.0 { average(self.scattering) / extinction } else { 0.0 }
+    }
+
+    /* density of scattering from travelling along dir to travelling along new_dir */
+    pub fn phase(&self, dir: Vector3<f32>, new_dir: Vector3<f32>) -> f32 {
+        let cos = dir.dot(new_dir);
+        let denominator = 1.0 + self.g * self.g - 2.0 * self.g * cos;
+        (1.0 - self.g * self.g) / (4.0 * consts::PI * denominator * denominator.sqrt())
+    }
+
+    /* picks a new direction in proportion to the phase function */
+    pub fn sample_phase(&self, dir: Vector3<f32>, sample: (f32, f32)) -> Vector3<f32> {
+        let g = self.g;
+        let cos = if g.abs() < 1.0e-3 {
+            1.0 - 2.0 * sample.0
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * sample.0);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let cos = cos.clamp(-1.0, 1.0);
+        let sin = (1.0 - cos * cos).sqrt();
+        let phi = 2.0 * consts::PI * sample.1;
+        let (u, v) = orthonormal_basis(dir);
+        (cos * dir + sin * phi.cos() * u + sin * phi.sin() * v).normalize()
+    }
+}
+
+impl<'a> MediumSegment<'a> {
+    fn density(&self, t: f32) -> f32 {
+        self.grid.map_or(1.0, |grid| grid.density(self.pos + t * self.dir))
+    }
+
+    fn absorption(&self) -> Color {
+        self.channels.color(self.medium.absorption)
+    }
+
+    fn scattering(&self) -> Color {
+        self.channels.color(self.medium.scattering)
+    }
+
+    /* a bound on the extinction of every channel everywhere along the segment */
+    fn majorant(&self) -> f32 {
+        let extinction = self.absorption() + self.scattering();
+        extinction.x.max(extinction.y).max(extinction.z) * self.grid.map_or(1.0, |grid| grid.max_density)
+    }
+
+    /* delta tracking: steps of exponentially distributed length against the majorant, at each
+     * of which the ray is absorbed, scattered or carries on (a null collision). with colored
+     * coefficients the choice follows the weight so far and the weight makes up the difference */
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> MediumEvent {
+        let majorant = self.majorant();
+        let (medium_absorption, medium_scattering) = (self.absorption(), self.scattering());
+        let mut weight = Vector3::new(1.0, 1.0, 1.0);
+        if majorant <= 0.0 {
+            return MediumEvent::Pass { weight };
+        }
+
+        let mut t = self.start;
+        loop {
+            t -= (1.0 - sampler.next_1d()).ln() / majorant;
+            if t >= self.end {
+                return MediumEvent::Pass { weight };
+            }
+
+            let density = self.density(t);
+            let absorption = density * medium_absorption;
+            let scattering = density * medium_scattering;
+            let null = Vector3::new(majorant, majorant, majorant) - absorption - scattering;
+
+            let p_absorb = average(weight.mul_element_wise(absorption));
+            let p_scatter = average(weight.mul_element_wise(scattering));
+            let p_null = average(weight.mul_element_wise(null));
+            let p_range = p_absorb + p_scatter + p_null;
+            if p_range <= 0.0 {
+                return MediumEvent::Absorb;
+            }
+
+            let rnd = sampler.next_1d() * p_range;
+            if rnd < p_absorb {
+                return MediumEvent::Absorb;
+            } else if rnd < p_absorb + p_scatter {
+                weight = weight.mul_element_wise(scattering) * p_range / (majorant * p_scatter);
+                return MediumEvent::Scatter { distance: t, weight };
+            }
+            weight = weight.mul_element_wise(null) * p_range / (majorant * p_null);
+        }
+    }
+
+    /* ratio tracking: the same steps as delta tracking, but every collision only scales down
+     * how much light gets through */
+    pub fn transmittance(&self, sampler: &mut dyn Sampler) -> Color {
+        let majorant = self.majorant();
+        let medium_extinction = self.absorption() + self.scattering();
+        let mut transmittance = Vector3::new(1.0, 1.0, 1.0);
+        if majorant <= 0.0 {
+            return transmittance;
+        }
+
+        let mut t = self.start;
+        loop {
+            t -= (1.0 - sampler.next_1d()).ln() / majorant;
+            if t >= self.end || transmittance == Vector3::new(0.0, 0.0, 0.0) {
+                return transmittance;
+            }
+            let extinction = self.density(t) * medium_extinction;
+            let null = Vector3::new(majorant, majorant, majorant) - extinction;
+            transmittance = transmittance.mul_element_wise(null) / majorant;
+        }
+    }
+}
+
+fn average(color: Color) -> f32 {
+    (color.x + color.y + color.z) / 3.0
+}
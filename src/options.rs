@@ -0,0 +1,244 @@
+use std::env;
+use std::str::FromStr;
+
+use sampler::*;
+use render::{Rect, TileOrder};
+
+pub struct Options {
+    pub scene: String,
+    pub output: String,
+    pub checkpoint: String,
+    pub checkpoint_interval: u64,
+    pub resume: bool,
+    pub initial_samples: Option<u32>,
+    pub max_samples: Option<u32>,
+    pub threshold: Option<f32>,
+    pub sampler: Option<SamplerType>,
+    pub seed: Option<u32>,
+    pub tile_size: Option<u32>,
+    pub tile_order: Option<TileOrder>,
+    pub crop: Option<Crop>,
+    pub crop_output: CropOutput,
+    pub heatmap: Option<String>,
+    pub workers: Vec<String>,
+    pub worker_timeout: u64,
+    pub serve: Option<String>,
+}
+
+/* corners of the region to render, x0,y0 inclusive and x1,y1 exclusive */
+#[derive(Debug, Clone, Copy)]
+pub enum Crop {
+    Pixels(u32, u32, u32, u32),
+    Normalized(f32, f32, f32, f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropOutput {
+    /* just the cropped region */
+    Cropped,
+    /* the whole frame, black outside the cropped region */
+    Full,
+}
+
+impl Crop {
+    pub fn rect(&self, width: u32, height: u32) -> Rect {
+        let (x0, y0, x1, y1) = match *self {
+            Crop::Pixels(x0, y0, x1, y1) => (x0, y0, x1, y1),
+            Crop::Normalized(x0, y0, x1, y1) => {
+                ((x0 * width as f32).floor() as u32, (y0 * height as f32).floor() as u32,
+                 (x1 * width as f32).ceil() as u32, (y1 * height as f32).ceil() as u32)
+            }
+        };
+
+        let x0 = x0.min(width);
+        let y0 = y0.min(height);
+        let x1 = x1.min(width);
+        let y1 = y1.min(height);
+        if x1 <= x0 || y1 <= y0 {
+            panic!("crop region is empty");
+        }
+
+        Rect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 }
+    }
+}
+
+const USAGE: &str = "usage: prj12 <scene.xml> <out.png> [options]
+       prj12 <scene.xml> --serve <address>
+
+options:
+    --checkpoint <file>            where to write checkpoints (default: <out.png>.ckpt)
+    --checkpoint-interval <secs>   seconds between checkpoints, 0 to only checkpoint on interrupt (default: 300)
+    --resume                       continue rendering from the checkpoint file
+    --initial-samples <n>          samples per pixel before checking for convergence
+    --max-samples <n>              samples per pixel to stop at even if not converged
+    --threshold <error>            relative standard error at which a tile counts as converged
+    --sampler <random|sobol|pmj02> sample sequence used for every random decision (default: sobol)
+    --seed <n>                     seed for the sampler, the same seed gives the same image (default: 0)
+    --tile-size <pixels>           width and height of the tiles the image is rendered in (default: 16)
+    --tile-order <order>           scanline, spiral or hilbert (default: spiral)
+    --crop <x0,y0,x1,y1>           only render the pixels from (x0, y0) up to but not including (x1, y1)
+    --crop-window <x0,y0,x1,y1>    the same, given as fractions of the image width and height
+    --crop-output <cropped|full>   write only the cropped region, or the whole frame with the rest black (default: full)
+    --heatmap <file.png>           also write an image of the number of samples taken per pixel
+    --workers <address,...>        render tiles on worker processes instead of locally
+    --worker-timeout <secs>        seconds to wait on a tile before giving it to another worker, 0 to wait forever (default: 600)
+    --serve <address>              run as a worker, rendering tiles for a coordinator
+
+addresses are host:port for tcp or unix:/path/to/socket for unix sockets. workers need to
+be given the same scene file as the coordinator.";
+
+pub fn parse_args() -> Options {
+    let mut positional = Vec::new();
+    let mut checkpoint = None;
+    let mut checkpoint_interval = 300;
+    let mut resume = false;
+    let mut initial_samples = None;
+    let mut max_samples = None;
+    let mut threshold = None;
+    let mut sampler = None;
+    let mut seed = None;
+    let mut tile_size = None;
+    let mut tile_order = None;
+    let mut crop = None;
+    let mut crop_output = CropOutput::Full;
+    let mut workers = Vec::new();
+    let mut worker_timeout = 600;
+    let mut serve = None;
+    let mut heatmap = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--checkpoint" => {
+                checkpoint = Some(next_value(&mut args, "--checkpoint"));
+            },
+            "--checkpoint-interval" => {
+                checkpoint_interval = next_value(&mut args, "--checkpoint-interval")
+                    .parse().expect("could not parse checkpoint interval");
+            },
+            "--resume" => {
+                resume = true;
+            },
+            "--initial-samples" => {
+                let samples = next_value(&mut args, "--initial-samples")
+                    .parse().expect("could not parse initial sample count");
+                if samples == 0 {
+                    panic!("initial sample count must be positive");
+                }
+                initial_samples = Some(samples);
+            },
+            "--max-samples" => {
+                max_samples = Some(next_value(&mut args, "--max-samples")
+                    .parse().expect("could not parse max sample count"));
+            },
+            "--threshold" => {
+                threshold = Some(next_value(&mut args, "--threshold")
+                    .parse().expect("could not parse sampling threshold"));
+            },
+            "--sampler" => {
+                sampler = Some(SamplerType::from_name(&next_value(&mut args, "--sampler"))
+                    .expect("unknown sampler type"));
+            },
+            "--seed" => {
+                seed = Some(next_value(&mut args, "--seed")
+                    .parse().expect("could not parse seed"));
+            },
+            "--tile-size" => {
+                let size = next_value(&mut args, "--tile-size")
+                    .parse().expect("could not parse tile size");
+                if size == 0 {
+                    panic!("tile size must be positive");
+                }
+                tile_size = Some(size);
+            },
+            "--tile-order" => {
+                tile_order = Some(TileOrder::from_name(&next_value(&mut args, "--tile-order"))
+                    .expect("unknown tile order"));
+            },
+            "--crop" => {
+                let (x0, y0, x1, y1) = parse_corners(&next_value(&mut args, "--crop"));
+                crop = Some(Crop::Pixels(x0, y0, x1, y1));
+            },
+            "--crop-window" => {
+                let (x0, y0, x1, y1) = parse_corners(&next_value(&mut args, "--crop-window"));
+                crop = Some(Crop::Normalized(x0, y0, x1, y1));
+            },
+            "--crop-output" => {
+                crop_output = match next_value(&mut args, "--crop-output").as_ref() {
+                    "cropped" => CropOutput::Cropped,
+                    "full" => CropOutput::Full,
+                    _ => panic!("unknown crop output, expected cropped or full"),
+                };
+            },
+            "--heatmap" => {
+                heatmap = Some(next_value(&mut args, "--heatmap"));
+            },
+            "--workers" => {
+                workers.extend(next_value(&mut args, "--workers").split(',').map(|worker| worker.trim().to_string()));
+            },
+            "--worker-timeout" => {
+                worker_timeout = next_value(&mut args, "--worker-timeout")
+                    .parse().expect("could not parse worker timeout");
+            },
+            "--serve" => {
+                serve = Some(next_value(&mut args, "--serve"));
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                ::std::process::exit(0);
+            },
+            _ => {
+                if arg.starts_with("--") {
+                    panic!("unknown option {}\n\n{}", arg, USAGE);
+                }
+                positional.push(arg);
+            }
+        }
+    }
+
+    if serve.is_some() {
+        if positional.is_empty() {
+            panic!("please provide a scene description file\n\n{}", USAGE);
+        }
+    } else if positional.len() < 2 {
+        panic!("please provide a scene description file and an image output file\n\n{}", USAGE);
+    }
+    let output = positional.get(1).cloned().unwrap_or_default();
+
+    Options {
+        scene: positional[0].clone(),
+        checkpoint: checkpoint.unwrap_or_else(|| format!("{}.ckpt", output)),
+        output,
+        checkpoint_interval,
+        resume,
+        initial_samples,
+        max_samples,
+        threshold,
+        sampler,
+        seed,
+        tile_size,
+        tile_order,
+        crop,
+        crop_output,
+        heatmap,
+        workers,
+        worker_timeout,
+        serve,
+    }
+}
+
+fn parse_corners<T: FromStr>(value: &str) -> (T, T, T, T) {
+    let corners: Vec<T> = value.split(',')
+        .map(|corner| corner.trim().parse().ok().expect("could not parse crop corner"))
+        .collect();
+    if corners.len() != 4 {
+        panic!("crop takes four comma-separated values x0,y0,x1,y1");
+    }
+
+    let mut corners = corners.into_iter();
+    (corners.next().unwrap(), corners.next().unwrap(), corners.next().unwrap(), corners.next().unwrap())
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> String {
+    args.next().unwrap_or_else(|| panic!("no value given for {}", option))
+}
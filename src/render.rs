@@ -0,0 +1,263 @@
+use scene::*;
+use film::*;
+use sampler::*;
+
+use std::mem;
+use std::io;
+use std::io::prelude::*;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rayon;
+use rayon::prelude::*;
+
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None,
+        }
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/* splits region into tiles, in the order they should be handed out */
+pub fn tiles(region: Rect, tile_size: u32, order: TileOrder) -> Vec<Rect> {
+    let columns = region.width.div_ceil(tile_size);
+    let rows = region.height.div_ceil(tile_size);
+
+    let mut coords: Vec<(u32, u32)> = Vec::with_capacity((columns * rows) as usize);
+    match order {
+        TileOrder::Scanline => {
+            for row in 0..rows {
+                for column in 0..columns {
+                    coords.push((column, row));
+                }
+            }
+        },
+        TileOrder::Spiral => {
+            /* walk a square spiral out from the center tile, keeping the steps that land on the grid */
+            let mut column = (columns as i64 - 1) / 2;
+            let mut row = (rows as i64 - 1) / 2;
+            let (mut dx, mut dy) = (1, 0);
+            let mut leg = 1;
+            while coords.len() < (columns * rows) as usize {
+                for _ in 0..2 {
+                    for _ in 0..leg {
+                        if 0 <= column && column < columns as i64 && 0 <= row && row < rows as i64 {
+                            coords.push((column as u32, row as u32));
+                        }
+                        column += dx;
+                        row += dy;
+                    }
+                    let turned = (-dy, dx);
+                    dx = turned.0;
+                    dy = turned.1;
+                }
+                leg += 1;
+            }
+        },
+        TileOrder::Hilbert => {
+            let mut order = 1;
+            while order < columns.max(rows) {
+                order *= 2;
+            }
+            for row in 0..rows {
+                for column in 0..columns {
+                    coords.push((column, row));
+                }
+            }
+            coords.sort_by_key(|&(column, row)| hilbert_index(order, column, row));
+        },
+    }
+
+    coords.into_iter().map(|(column, row)| {
+        let x = region.x + column * tile_size;
+        let y = region.y + row * tile_size;
+        Rect {
+            x,
+            y,
+            width: tile_size.min(region.x + region.width - x),
+            height: tile_size.min(region.y + region.height - y),
+        }
+    }).collect()
+}
+
+/* distance along a hilbert curve filling an n by n grid, n a power of two */
+fn hilbert_index(n: u32, x: u32, y: u32) -> u64 {
+    let (mut x, mut y) = (x, y);
+    let mut d: u64 = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u32;
+        let ry = ((y & s) > 0) as u32;
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+/* renders region of film tile by tile. tiles are started in the order given by
+ * sampling.tile_order and rendered into their own buffers, which get merged back into film on
+ * the calling thread, where after_tile is also called. */
+pub fn render<F, G>(film: &mut Film, region: Rect, sampling: &Sampling, sample_pixel: &F, mut after_tile: G)
+    where F: Fn(u32, u32, &mut dyn Sampler) -> Color + Sync, G: FnMut(&Film)
+{
+    let tiles = tiles(region, sampling.tile_size, sampling.tile_order);
+    let buffers: Vec<Mutex<Vec<Pixel>>> = tiles.iter().map(|tile| Mutex::new(film.read_tile(*tile))).collect();
+    let next_tile = AtomicUsize::new(0);
+
+    let mut progress = Progress::new(tiles.len(), (region.width * region.height) as usize);
+    progress.print();
+
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+
+    thread::scope(|scope| {
+        let tiles = &tiles;
+        let buffers = &buffers;
+        let next_tile = &next_tile;
+
+        scope.spawn(move || {
+            /* one long-running job per thread pulling tiles off a shared counter, so tiles start
+             * in order instead of however work stealing would split them up */
+            (0..rayon::current_num_threads()).into_par_iter().for_each(|_| {
+                let sender = sender.lock().unwrap().clone();
+                loop {
+                    let i = next_tile.fetch_add(1, Ordering::SeqCst);
+                    if i >= tiles.len() || interrupted() {
+                        break;
+                    }
+
+                    let mut pixels = mem::take(&mut *buffers[i].lock().unwrap());
+                    render_tile(tiles[i], &mut pixels, sampling, sample_pixel);
+                    sender.send((tiles[i], pixels)).unwrap();
+                }
+            });
+        });
+
+        for (tile, pixels) in receiver {
+            film.write_tile(tile, &pixels);
+            progress.finish_tile(tile);
+            progress.print();
+            after_tile(film);
+        }
+    });
+
+    eprintln!();
+}
+
+/* adaptively samples one tile: every pixel gets num_samples samples, and num_samples doubles
+ * until the tile's average relative error drops below the threshold */
+pub fn render_tile<F>(tile: Rect, pixels: &mut [Pixel], sampling: &Sampling, sample_pixel: &F)
+    where F: Fn(u32, u32, &mut dyn Sampler) -> Color
+{
+    /* resumed tiles pick the sample count back up where the checkpoint left it */
+    let mut num_samples = pixels.iter().map(|pixel| pixel.samples).fold(sampling.initial_samples, u32::max);
+
+    loop {
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            if interrupted() {
+                return;
+            }
+
+            let x = tile.x + i as u32 % tile.width;
+            let y = tile.y + i as u32 / tile.width;
+            for index in (pixel.samples + 1)..(num_samples + 1) {
+                let mut sampler = sampling.sampler.sampler(sampling.seed, x, y, index);
+                pixel.add_sample(sample_pixel(x, y, &mut *sampler));
+            }
+        }
+
+        /* averaging over the tile keeps a lone firefly from holding it back */
+        let error = pixels.iter().map(|pixel| pixel.relative_error()).sum::<f32>() / pixels.len() as f32;
+        if num_samples < sampling.max_samples && error > sampling.threshold {
+            num_samples = (num_samples * 2).min(sampling.max_samples);
+        } else {
+            break;
+        }
+    }
+}
+
+pub struct Progress {
+    start: Instant,
+    tiles: usize,
+    tiles_done: usize,
+    pixels: usize,
+    pixels_done: usize,
+}
+
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+impl Progress {
+    pub fn new(tiles: usize, pixels: usize) -> Progress {
+        Progress {
+            start: Instant::now(),
+            tiles,
+            tiles_done: 0,
+            pixels,
+            pixels_done: 0,
+        }
+    }
+
+    pub fn finish_tile(&mut self, tile: Rect) {
+        self.tiles_done += 1;
+        self.pixels_done += (tile.width * tile.height) as usize;
+    }
+
+    pub fn print(&self) {
+        let fraction = if self.pixels == 0 { 1.0 } else { self.pixels_done as f64 / self.pixels as f64 };
+        let filled = (fraction * PROGRESS_BAR_WIDTH as f64) as usize;
+        let elapsed = self.start.elapsed();
+
+        let eta = if self.pixels_done > 0 {
+            let remaining = elapsed.as_secs_f64() * (1.0 - fraction) / fraction;
+            format_duration(Duration::from_secs(remaining as u64))
+        } else {
+            "?".to_string()
+        };
+
+        eprint!("\r[{}{}] {:3}% {}/{} tiles, elapsed {}, eta {}  ",
+                "#".repeat(filled), "-".repeat(PROGRESS_BAR_WIDTH - filled),
+                (fraction * 100.0) as u32, self.tiles_done, self.tiles,
+                format_duration(elapsed), eta);
+        io::stderr().flush().ok();
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
@@ -0,0 +1,392 @@
+extern crate rand;
+
+use std::f32::consts;
+use std::sync::OnceLock;
+use self::rand::{Rng, SeedableRng, XorShiftRng};
+
+/* a source of sample values in [0, 1) for one camera sample. every call moves on to the next
+ * dimension, so a given dimension (pixel offset, lens, first bounce direction, ...) is
+ * stratified across all the samples taken in a pixel. */
+pub trait Sampler {
+    fn next_1d(&mut self) -> f32;
+    fn next_2d(&mut self) -> (f32, f32);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerType {
+    Random,
+    Sobol,
+    Pmj02,
+}
+
+impl SamplerType {
+    pub fn from_name(name: &str) -> Option<SamplerType> {
+        match name {
+            "random" => Some(SamplerType::Random),
+            "sobol" => Some(SamplerType::Sobol),
+            "pmj02" => Some(SamplerType::Pmj02),
+            _ => None,
+        }
+    }
+
+    /* sampler for sample number index of pixel (x, y). everything it returns is a function of
+     * (seed, x, y, index) alone, so renders don't depend on how work is spread across threads. */
+    pub fn sampler(&self, seed: u32, x: u32, y: u32, index: u32) -> Box<dyn Sampler> {
+        let pixel_seed = hash(hash(seed, x), y);
+        match *self {
+            SamplerType::Random => Box::new(RandomSampler::new(hash(pixel_seed, index))),
+            SamplerType::Sobol => Box::new(SobolSampler { pixel_seed, index, dimension: 0 }),
+            SamplerType::Pmj02 => Box::new(Pmj02Sampler { pixel_seed, index, dimension: 0 }),
+        }
+    }
+}
+
+/* independent uniform random numbers, no stratification at all */
+pub struct RandomSampler {
+    rng: XorShiftRng,
+}
+
+impl RandomSampler {
+    fn new(seed: u32) -> RandomSampler {
+        /* xorshift must not be seeded with all zeros */
+        let state = [hash(seed, 1), hash(seed, 2), hash(seed, 3), hash(seed, 4) | 1];
+        RandomSampler { rng: XorShiftRng::from_seed(state) }
+    }
+}
+
+impl Sampler for RandomSampler {
+    fn next_1d(&mut self) -> f32 {
+        self.rng.next_f32()
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.rng.next_f32(), self.rng.next_f32())
+    }
+}
+
+/* the first two sobol dimensions, Owen-scrambled and with the sample order shuffled per pixel
+ * and per dimension, following Burley, "Practical Hash-based Owen Scrambling" (2020) */
+pub struct SobolSampler {
+    pixel_seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl Sampler for SobolSampler {
+    fn next_1d(&mut self) -> f32 {
+        let seed = hash(self.pixel_seed, self.dimension);
+        self.dimension += 1;
+
+        let index = nested_uniform_scramble(self.index, seed);
+        to_unit_float(nested_uniform_scramble(sobol(index, 0), hash(seed, 0)))
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let seed = hash(self.pixel_seed, self.dimension);
+        self.dimension += 1;
+
+        let index = nested_uniform_scramble(self.index, seed);
+        (to_unit_float(nested_uniform_scramble(sobol(index, 0), hash(seed, 0))),
+         to_unit_float(nested_uniform_scramble(sobol(index, 1), hash(seed, 1))))
+    }
+}
+
+/* progressive multi-jittered (0,2) sequence, Christensen et al. 2018. a single precomputed
+ * table is decorrelated between pixels and dimensions the same way as the sobol points. */
+pub struct Pmj02Sampler {
+    pixel_seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+const PMJ02_SAMPLES: usize = 4096;
+
+impl Sampler for Pmj02Sampler {
+    fn next_1d(&mut self) -> f32 {
+        self.next_2d().0
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let table = pmj02_table();
+
+        /* past the end of the table start over on a differently shuffled copy */
+        let repeat = self.index / PMJ02_SAMPLES as u32;
+        let seed = hash(hash(self.pixel_seed, self.dimension), repeat);
+        self.dimension += 1;
+
+        let index = nested_uniform_scramble(self.index % PMJ02_SAMPLES as u32, seed) as usize % PMJ02_SAMPLES;
+        let (x, y) = table[index];
+        (to_unit_float(nested_uniform_scramble(x, hash(seed, 0))),
+         to_unit_float(nested_uniform_scramble(y, hash(seed, 1))))
+    }
+}
+
+fn pmj02_table() -> &'static [(u32, u32)] {
+    static TABLE: OnceLock<Vec<(u32, u32)>> = OnceLock::new();
+    TABLE.get_or_init(|| generate_pmj02(PMJ02_SAMPLES))
+}
+
+/* the points are stored as 32-bit fixed point so they can be scrambled like sobol points */
+fn generate_pmj02(num_samples: usize) -> Vec<(u32, u32)> {
+    let mut generator = Pmj02Generator {
+        rng: XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]),
+        samples: Vec::with_capacity(num_samples),
+        occupied: Vec::new(),
+    };
+
+    let first = (generator.rng.next_f64(), generator.rng.next_f64());
+    generator.samples.push(first);
+
+    let mut n = 1;
+    while n < num_samples {
+        generator.extend_even(n);
+        if 2 * n < num_samples {
+            generator.extend_odd(2 * n);
+        }
+        n *= 4;
+    }
+
+    generator.samples.truncate(num_samples);
+    generator.samples.iter().map(|&(x, y)| ((x * 4294967296.0) as u32, (y * 4294967296.0) as u32)).collect()
+}
+
+struct Pmj02Generator {
+    rng: XorShiftRng,
+    samples: Vec<(f64, f64)>,
+    /* for each elementary interval shape, which strata already hold a sample */
+    occupied: Vec<Vec<bool>>,
+}
+
+impl Pmj02Generator {
+    /* n samples (a power of 4) to 2n: each new sample goes in the subquadrant diagonally
+     * opposite an old one */
+    fn extend_even(&mut self, n: usize) {
+        let cells = (n as f64).sqrt() as usize;
+        self.mark_occupied(2 * n);
+
+        for s in 0..n {
+            let (i, j, x_half, y_half) = Self::subquadrant(self.samples[s], cells);
+            let sample = self.generate(i, j, 1 - x_half, 1 - y_half, cells, 2 * n);
+            self.samples.push(sample);
+        }
+    }
+
+    /* n samples (twice a power of 4) to 2n: fill the two subquadrants left empty in each cell */
+    fn extend_odd(&mut self, n: usize) {
+        let cells = ((n / 2) as f64).sqrt() as usize;
+        self.mark_occupied(2 * n);
+
+        let mut halves = Vec::with_capacity(n / 2);
+        for s in 0..n / 2 {
+            let (i, j, mut x_half, mut y_half) = Self::subquadrant(self.samples[s], cells);
+            if self.rng.gen::<bool>() {
+                x_half = 1 - x_half;
+            } else {
+                y_half = 1 - y_half;
+            }
+            halves.push((x_half, y_half));
+
+            let sample = self.generate(i, j, x_half, y_half, cells, 2 * n);
+            self.samples.push(sample);
+        }
+
+        for (s, &(x_half, y_half)) in halves.iter().enumerate() {
+            let (i, j, _, _) = Self::subquadrant(self.samples[s], cells);
+            let sample = self.generate(i, j, 1 - x_half, 1 - y_half, cells, 2 * n);
+            self.samples.push(sample);
+        }
+    }
+
+    fn subquadrant(sample: (f64, f64), cells: usize) -> (usize, usize, usize, usize) {
+        let i = (sample.0 * cells as f64) as usize;
+        let j = (sample.1 * cells as f64) as usize;
+        let x_half = ((sample.0 * cells as f64 - i as f64) * 2.0) as usize;
+        let y_half = ((sample.1 * cells as f64 - j as f64) * 2.0) as usize;
+        (i, j, x_half.min(1), y_half.min(1))
+    }
+
+    fn generate(&mut self, i: usize, j: usize, x_half: usize, y_half: usize, cells: usize, total: usize) -> (f64, f64) {
+        let mut sample;
+        let mut attempts = 0;
+        loop {
+            sample = ((i as f64 + 0.5 * (x_half as f64 + self.rng.next_f64())) / cells as f64,
+                      (j as f64 + 0.5 * (y_half as f64 + self.rng.next_f64())) / cells as f64);
+            attempts += 1;
+            /* a valid point practically always turns up quickly, but never hang on it */
+            if !self.is_occupied(sample, total) || attempts > 100000 {
+                break;
+            }
+        }
+        self.mark(sample, total);
+        sample
+    }
+
+    fn mark_occupied(&mut self, total: usize) {
+        let shapes = total.trailing_zeros() as usize + 1;
+        self.occupied = vec![vec![false; total]; shapes];
+        for s in 0..self.samples.len() {
+            let sample = self.samples[s];
+            self.mark(sample, total);
+        }
+    }
+
+    fn mark(&mut self, sample: (f64, f64), total: usize) {
+        let mut x_divs = total;
+        let mut y_divs = 1;
+        for shape in 0..self.occupied.len() {
+            let stratum = Self::stratum(sample, x_divs, y_divs);
+            self.occupied[shape][stratum] = true;
+            x_divs /= 2;
+            y_divs *= 2;
+        }
+    }
+
+    fn is_occupied(&self, sample: (f64, f64), total: usize) -> bool {
+        let mut x_divs = total;
+        let mut y_divs = 1;
+        for shape in 0..self.occupied.len() {
+            if self.occupied[shape][Self::stratum(sample, x_divs, y_divs)] {
+                return true;
+            }
+            x_divs /= 2;
+            y_divs *= 2;
+        }
+        false
+    }
+
+    fn stratum(sample: (f64, f64), x_divs: usize, y_divs: usize) -> usize {
+        let x = ((sample.0 * x_divs as f64) as usize).min(x_divs - 1);
+        let y = ((sample.1 * y_divs as f64) as usize).min(y_divs - 1);
+        y * x_divs + x
+    }
+}
+
+/* dimension 0 is the van der Corput sequence, dimension 1 uses the primitive polynomial x + 1 */
+fn sobol(index: u32, dimension: u32) -> u32 {
+    let mut result = 0;
+    let mut v: u32 = 1 << 31;
+    let mut i = index;
+    while i != 0 {
+        if i & 1 != 0 {
+            result ^= v;
+        }
+        i >>= 1;
+        v = if dimension == 0 { v >> 1 } else { v ^ (v >> 1) };
+    }
+    result
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+pub fn hash(a: u32, b: u32) -> u32 {
+    /* boost::hash_combine over a murmur-style finalizer */
+    let mut h = a ^ b.wrapping_add(0x9e3779b9).wrapping_add(a << 6).wrapping_add(a >> 2);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
+
+fn to_unit_float(x: u32) -> f32 {
+    /* the top 24 bits, so the result can't round up to 1.0 */
+    (x >> 8) as f32 / 16777216.0
+}
+
+/* Shirley and Chiu's mapping from the square to the disk, which keeps stratified samples stratified */
+pub fn concentric_disk_sample(sample: (f32, f32)) -> (f32, f32) {
+    let a = 2.0 * sample.0 - 1.0;
+    let b = 2.0 * sample.1 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, consts::PI / 4.0 * (b / a))
+    } else {
+        (b, consts::PI / 2.0 - consts::PI / 4.0 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+/* a piecewise constant distribution over the unit square, from weights on a grid of cells */
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    width: usize,
+    height: usize,
+    /* cumulative distribution over the rows, then over the cells within each row */
+    row_cdf: Vec<f32>,
+    cell_cdf: Vec<f32>,
+    /* density of each cell */
+    pdf: Vec<f32>,
+}
+
+impl Distribution2D {
+    /* weights are given row by row, None if they're all zero */
+    pub fn new(width: usize, height: usize, weights: &[f32]) -> Option<Distribution2D> {
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut cell_cdf = Vec::with_capacity(width * height);
+        let mut total = 0.0;
+        for row in weights.chunks(width) {
+            let mut row_total = 0.0;
+            for &weight in row {
+                row_total += weight.max(0.0);
+                cell_cdf.push(row_total);
+            }
+            let start = cell_cdf.len() - width;
+            if row_total > 0.0 {
+                for value in &mut cell_cdf[start..] {
+                    *value /= row_total;
+                }
+            }
+            total += row_total;
+            row_cdf.push(total);
+        }
+
+        if total.is_nan() || total <= 0.0 {
+            return None;
+        }
+        for value in &mut row_cdf {
+            *value /= total;
+        }
+        let pdf = weights.iter().map(|&weight| weight.max(0.0) * (width * height) as f32 / total).collect();
+
+        Some(Distribution2D { width, height, row_cdf, cell_cdf, pdf })
+    }
+
+    /* a point in the unit square, x across and y down the rows, and its density */
+    pub fn sample(&self, sample: (f32, f32)) -> ((f32, f32), f32) {
+        let (row, v) = sample_cdf(&self.row_cdf, sample.0);
+        let (column, u) = sample_cdf(&self.cell_cdf[row * self.width..(row + 1) * self.width], sample.1);
+
+        let x = (column as f32 + u) / self.width as f32;
+        let y = (row as f32 + v) / self.height as f32;
+        ((x, y), self.pdf[row * self.width + column])
+    }
+
+    pub fn pdf(&self, x: f32, y: f32) -> f32 {
+        let column = ((x * self.width as f32) as usize).min(self.width - 1);
+        let row = ((y * self.height as f32) as usize).min(self.height - 1);
+        self.pdf[row * self.width + column]
+    }
+}
+
+/* the bucket value falls in, and where in that bucket it falls */
+fn sample_cdf(cdf: &[f32], value: f32) -> (usize, f32) {
+    let i = cdf.partition_point(|&c| c <= value).min(cdf.len() - 1);
+    let start = if i == 0 { 0.0 } else { cdf[i - 1] };
+    let t = if cdf[i] > start { (value - start) / (cdf[i] - start) } else { 0.5 };
+    (i, t.clamp(0.0, 1.0))
+}
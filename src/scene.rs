@@ -1,18 +1,35 @@
 extern crate cgmath;
-extern crate rand;
 
 use std::collections::HashMap;
+use std::f32;
 use std::f32::consts;
+use std::ptr;
 use self::cgmath::{Vector3, Matrix3, SquareMatrix, InnerSpace, Matrix, ElementWise, Zero, One};
 use geometry::*;
+use light::*;
+use medium::*;
+use bvh::BoundingBox;
+use sky::Sky;
+use spectrum::*;
+use fresnel::Conductor;
+use layers::*;
+use sampler::*;
+use render::TileOrder;
 
 #[derive(Debug)]
 pub struct Scene {
     pub nodes: Vec<Node>,
     pub materials: HashMap<String, Material>,
     pub lights: Vec<Light>,
+    pub emitters: Vec<Emitter>,
     pub background: Texture,
     pub environment: Texture,
+    pub environment_light: Option<EnvironmentLight>,
+    /* fog filling the bounds of the scene */
+    pub medium: Option<Medium>,
+    pub bounds: BoundingBox,
+    /* carry wavelengths along paths instead of red, green and blue */
+    pub spectral: bool,
 }
 
 #[derive(Debug)]
@@ -33,14 +50,32 @@ pub struct Object {
 pub struct Material {
     pub diffuse: Texture,
     pub specular: Texture,
-    pub glossiness: f32,
-    pub emission: Color,
+    pub glossiness: Texture,
+    pub emission: Texture,
     pub reflection: Texture,
-    pub reflection_glossiness: f32,
+    pub reflection_glossiness: Texture,
     pub refraction: Texture,
-    pub refraction_glossiness: f32,
-    pub refraction_index: f32,
+    pub refraction_glossiness: Texture,
+    pub refraction_index: RefractionIndex,
+    /* the index above is multiplied by this, so it can change over the surface */
+    pub refraction_index_scale: Texture,
     pub absorption: Color,
+    /* what fills the inside of a closed sphere or mesh */
+    pub medium: Option<Medium>,
+    /* light crosses the surface diffusely and wanders through the medium inside before it
+     * comes back out */
+    pub subsurface: bool,
+    /* reflection is tinted by a metal's Fresnel reflectance */
+    pub conductor: Option<Conductor>,
+    pub clearcoat: Option<Clearcoat>,
+    pub sheen: Option<Sheen>,
+    /* tangent space normals, x along u, y along v and z out of the surface */
+    pub normal_map: Option<Texture>,
+    /* heights, whose slopes tilt the normal */
+    pub bump: Option<Texture>,
+    /* how much of the surface is there. rays pass through where it's below a half, as if the
+     * surface was cut out */
+    pub opacity: Option<Texture>,
 }
 
 #[derive(Debug)]
@@ -53,35 +88,54 @@ pub struct Texture {
 #[derive(Debug)]
 pub enum TextureData {
     Blank,
-    Image { pixels: Vec<u8>, width: usize, height: usize },
+    /* rgba, 16 bits a channel whatever the file had, and whether the file had alpha */
+    Image { pixels: Vec<u16>, width: usize, height: usize, alpha: bool, mipmap: Vec<MipLevel> },
+    /* linear rgb floats from .hdr and .exr files */
+    HdrImage { pixels: Vec<f32>, width: usize, height: usize, mipmap: Vec<MipLevel> },
     Checkerboard { color1: Color, color2: Color },
+    /* daylight, looked up by the direction an environment's uv coordinates map to */
+    Sky(Sky),
+}
+
+/* one level of an image's MIP map, each texel the average of four in the level above. the
+ * image itself is the level above the first */
+#[derive(Debug)]
+pub struct MipLevel {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
 }
 
 pub type Color = Vector3<f32>;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Light {
     pub intensity: f32,
     pub color: Color,
     pub light_type: LightType,
+    /* how the intensity of a point or spot light varies with direction */
+    pub profile: Option<IesProfile>,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum LightType {
     Ambient,
-    Directional(Vector3<f32>),
-    Point { position: Vector3<f32>, size: f32 },
+    /* a disk of light cos_radius across, like the sun, or a single direction when it's 1 */
+    Directional { direction: Vector3<f32>, cos_radius: f32 },
+    Point { position: Vector3<f32> },
+    /* full intensity inside cos_inner, fading out towards cos_outer */
+    Spot { position: Vector3<f32>, direction: Vector3<f32>, cos_inner: f32, cos_outer: f32 },
 }
 
-pub struct Camera {
-    pub pos: Vector3<f32>,
-    pub dir: Vector3<f32>,
-    pub up: Vector3<f32>,
-    pub fov: f32,
-    pub img_width: u32,
-    pub img_height: u32,
-    pub focaldist: f32,
-    pub dof: f32,
+#[derive(Debug, Clone, Copy)]
+pub struct Sampling {
+    pub initial_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f32,
+    pub sampler: SamplerType,
+    pub seed: u32,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
 }
 
 pub struct HitInfo {
@@ -89,7 +143,12 @@ pub struct HitInfo {
     pub pos: Vector3<f32>,
     pub uv: Vector3<f32>,
     pub normal: Vector3<f32>,
+    /* how far pos moves for a step in the texture's u and in v, dP/du and dP/dv */
+    pub tangent: Vector3<f32>,
+    pub bitangent: Vector3<f32>,
     pub side: Side,
+    /* how wide a pixel is in uv where the ray hit, 0 when that isn't known */
+    pub footprint: f32,
 }
 
 #[derive(PartialEq, Debug)]
@@ -104,107 +163,508 @@ pub struct Transform {
     pub translate: Vector3<f32>,
 }
 
-impl Default for Camera {
-    fn default() -> Camera {
-        Camera {
-            pos: Vector3::new(0.0, 0.0, 0.0),
-            dir: Vector3::new(0.0, 0.0, -1.0),
-            up: Vector3::new(0.0, 1.0, 0.0),
-            fov: 40.0,
-            img_width: 800,
-            img_height: 600,
-            focaldist: 1.0,
-            dof: 0.0,
+impl Default for Sampling {
+    fn default() -> Sampling {
+        Sampling {
+            initial_samples: 128,
+            max_samples: 512,
+            threshold: 0.05,
+            sampler: SamplerType::Sobol,
+            seed: 0,
+            tile_size: 16,
+            tile_order: TileOrder::Spiral,
         }
     }
 }
 
+/* how a ray came to be cast, which decides what it finds when it leaves the scene and whether
+ * it counts emission direct lighting already accounted for */
+#[derive(Clone, Copy)]
+pub enum Ray {
+    Camera { x: f32, y: f32, differential: Option<Differential> },
+    /* a diffuse bounce or scattering in a medium, in a direction picked with density pdf */
+    Scattered { pdf: f32 },
+    Specular { differential: Option<Differential> },
+}
+
+/* how a ray's origin and direction change from one pixel to the next, after Igehy (1999). it
+ * gives how much of a texture a pixel covers, and is followed through mirrors and glass but
+ * not diffuse bounces, which blur textures anyway */
+#[derive(Clone, Copy)]
+pub struct Differential {
+    pub dpdx: Vector3<f32>,
+    pub dpdy: Vector3<f32>,
+    pub dddx: Vector3<f32>,
+    pub dddy: Vector3<f32>,
+}
+
 pub const BIAS: f32 = 0.01;
+/* density of a uniform direction on the hemisphere, which is how diffuse bounces are picked */
+const DIFFUSE_PDF: f32 = 1.0 / (2.0 * consts::PI);
 pub const EPSILON: f32 = 1.0e-8;
+/* scattering events a subsurface walk may take before it's given up on */
+const MAX_SUBSURFACE_STEPS: usize = 4096;
 
 impl Scene {
-    pub fn sample(&self, pos: Vector3<f32>, dir: Vector3<f32>, x: f32, y: f32) -> Color {
-        self.cast(pos, dir, 1.0).unwrap_or_else(|| self.background.sample(Vector3::new(x, y, 0.0)))
+    pub fn sample(&self, pos: Vector3<f32>, dir: Vector3<f32>, x: f32, y: f32, differential: Option<Differential>, sampler: &mut dyn Sampler) -> Color {
+        let channels = if self.spectral { Channels::sample(sampler.next_1d()) } else { Channels::Rgb };
+        let ray = Ray::Camera { x, y, differential };
+        channels.to_rgb(self.cast(pos, dir, 1.0, ray, channels, sampler))
+    }
+
+    pub fn cast(&self, pos: Vector3<f32>, dir: Vector3<f32>, weight: f32, ray: Ray, channels: Channels, sampler: &mut dyn Sampler) -> Color {
+        let hit = self.intersect(pos, dir);
+
+        /* a medium along the way can scatter or absorb the ray before it gets anywhere */
+        let mut throughput = Vector3::new(1.0, 1.0, 1.0);
+        if let Some(segment) = self.segment_medium(pos, dir, hit.as_ref(), channels) {
+            match segment.sample(sampler) {
+                MediumEvent::Scatter { distance, weight: scatter_weight } => {
+                    let scatter_pos = pos + distance * dir;
+                    return scatter_weight.mul_element_wise(self.scatter(scatter_pos, dir, segment.medium, weight, channels, sampler));
+                },
+                MediumEvent::Absorb => return Vector3::zero(),
+                MediumEvent::Pass { weight } => throughput = weight,
+            }
+        }
+
+        let color = match hit {
+            Some(hit) => self.shade(hit, dir, weight, ray, channels, sampler),
+            None => match ray {
+                Ray::Camera { x, y, .. } => channels.color(self.background.sample(Vector3::new(x, y, 0.0))),
+                Ray::Scattered { pdf } => self.escape_scattered(dir, pdf, channels),
+                Ray::Specular { .. } => self.escape(dir, channels),
+            },
+        };
+        throughput.mul_element_wise(color)
     }
 
-    pub fn cast(&self, pos: Vector3<f32>, dir: Vector3<f32>, weight: f32) -> Option<Color> {
-        let result = self.intersect(pos, dir).map(|(hit_info, node)| {
-            let material = self.materials.get(&node.object.as_ref().unwrap().material[..])
-                .expect("material does not exist for object");
+    fn shade(&self, hit: (HitInfo, &Node), dir: Vector3<f32>, weight: f32, ray: Ray, channels: Channels, sampler: &mut dyn Sampler) -> Color {
+        let (mut hit_info, node) = hit;
+        let material = self.material(node);
+
+        let differential = ray.differential().map(|differential| differential.transfer(dir, hit_info.z, hit_info.normal));
+        hit_info.footprint = differential.map_or(0.0, |differential| differential.footprint(hit_info.tangent, hit_info.bitangent));
+
+        /* the ray carries on through surfaces that only mark where a medium starts */
+        if material.is_boundary() {
+            return self.cast(hit_info.pos + BIAS * dir, dir, weight, ray.with_differential(differential), channels, sampler);
+        }
+        if material.subsurface {
+            return self.subsurface(hit_info, material, dir, weight, channels, sampler);
+        }
+
+        let diffuse = channels.color(material.diffuse.sample_at(&hit_info));
+        let reflection = channels.color(material.reflection.sample_at(&hit_info));
+        let refraction = channels.color(material.refraction.sample_at(&hit_info));
+
+        let normal = match hit_info.side {
+            Side::Back => -material.shading_normal(&hit_info),
+            Side::Front => material.shading_normal(&hit_info),
+        };
+
+        let reflection = match material.conductor {
+            Some(ref conductor) => reflection.mul_element_wise(conductor.reflectance(normal.dot(-dir), channels)),
+            None => reflection,
+        };
+
+        /* layers on top reflect some of the light first, a clearcoat before a sheen, and the
+         * layers below only get what's left */
+        let clearcoat = match (material.clearcoat.as_ref(), &hit_info.side) {
+            (Some(clearcoat), &Side::Front) => clearcoat.reflectance(normal.dot(-dir)),
+            _ => 0.0,
+        };
+        let (sheen, sheen_albedo) = match material.sheen {
+            Some(ref sheen) => ((1.0 - clearcoat) * channels.color(sheen.color), sheen.albedo(normal.dot(-dir))),
+            None => (Vector3::zero(), 0.0),
+        };
+        let below = (1.0 - clearcoat) * Vector3::new(1.0, 1.0, 1.0) - sheen_albedo * sheen;
+        let diffuse = diffuse.mul_element_wise(below);
+        let reflection = reflection.mul_element_wise(below);
+        let refraction = refraction.mul_element_wise(below);
+
+        /* Schlick's approximation for Fresnel reflectance */
+        let index = material.index(&hit_info, channels);
+        let (n1, n2) = match hit_info.side {
+            Side::Back => (index, 1.0),
+            Side::Front => (1.0, index)
+        };
+        let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+        let ar = r0 + (1.0 - r0) * (1.0 - normal.dot(-dir)).powi(5);
+
+        let p_diffuse = (diffuse.x + diffuse.y + diffuse.z) / 3.0;
+        let p_refraction = (1.0 - ar) * (refraction.x + refraction.y + refraction.z) / 3.0;
+        let p_reflection = (1.0 + ar) * (reflection.x + reflection.y + reflection.z) / 3.0;
+        let p_clearcoat = clearcoat;
+        let p_sheen = sheen_albedo * (sheen.x + sheen.y + sheen.z) / 3.0;
+
+        let p_range = p_diffuse + p_reflection + p_refraction + p_clearcoat + p_sheen;
+
+        /* emission is left out of rays that direct lighting already accounted for, which
+         * doesn't sample surfaces whose emission changes over them */
+        let mut color = match ray {
+            Ray::Scattered { .. } if material.emission.is_constant() => Vector3::zero(),
+            _ => channels.color(material.emission.sample_at(&hit_info)),
+        };
+
+        /* Russian Roulette */
+        if p_range == 0.0 || sampler.next_1d() > weight {
+            return color;
+        }
 
-            let diffuse = material.diffuse.sample(hit_info.uv);
-            let reflection = material.reflection.sample(hit_info.uv);
-            let refraction = material.refraction.sample(hit_info.uv);
+        let p_diffuse = p_diffuse / p_range;
+        let p_reflection = p_reflection / p_range;
+        let p_refraction = p_refraction / p_range;
+        let p_clearcoat = p_clearcoat / p_range;
+        let p_sheen = p_sheen / p_range;
+
+        let rnd = sampler.next_1d();
+        let dir_sample = sampler.next_2d();
+        if rnd < p_diffuse {
+            /* uniform hemisphere sampling weighted by cos alone makes the diffuse brdf
+             * diffuse / 2pi, which direct lighting has to match */
+            let direct = self.direct_light(hit_info.pos, channels, sampler, |light_dir| (normal.dot(light_dir), DIFFUSE_PDF));
+            color += diffuse.mul_element_wise(direct) / (2.0 * consts::PI * p_diffuse);
+
+            let new_dir = random_hemisphere_sample(normal, dir_sample);
+            color += normal.dot(new_dir) * diffuse.mul_element_wise(self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_diffuse, Ray::Scattered { pdf: DIFFUSE_PDF }, channels, sampler)) / p_diffuse;
+        } else if rnd < p_diffuse + p_reflection {
+            let new_dir = random_rotation(reflect_ray(-dir, normal), material.reflection_glossiness.value_at(&hit_info), dir_sample);
+            let ray = Ray::Specular { differential: differential.map(|differential| differential.reflect(normal)) };
+            color += normal.dot(new_dir) * reflection.mul_element_wise(self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_reflection, ray, channels, sampler)) / p_reflection;
+        } else if rnd < p_diffuse + p_reflection + p_refraction {
+            let refracted = refract_ray(-dir, normal, n1, n2);
+            let new_dir = random_rotation(refracted.unwrap_or_else(|| reflect_ray(-dir, normal)), material.refraction_glossiness.value_at(&hit_info), dir_sample);
+            let ray = Ray::Specular { differential: differential.map(|differential| match refracted {
+                Some(refracted) => differential.refract(dir, refracted, normal, n1 / n2),
+                None => differential.reflect(normal),
+            }) };
+            /* the new direction is only right for the hero wavelength if the index depends on it */
+            let (channels, dispersion) = channels.refract(&material.refraction_index);
+            color += normal.dot(new_dir) * dispersion.mul_element_wise(refraction).mul_element_wise(self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_refraction, ray, channels, sampler)) / p_refraction;
+        } else if rnd < p_diffuse + p_reflection + p_refraction + p_clearcoat {
+            let glossiness = material.clearcoat.as_ref().map_or(0.0, |clearcoat| clearcoat.glossiness);
+            let new_dir = random_rotation(reflect_ray(-dir, normal), glossiness, dir_sample);
+            let ray = Ray::Specular { differential: differential.map(|differential| differential.reflect(normal)) };
+            color += normal.dot(new_dir) * clearcoat * self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_clearcoat, ray, channels, sampler) / p_clearcoat;
+        } else if rnd < p_diffuse + p_reflection + p_refraction + p_clearcoat + p_sheen {
+            /* sampled like diffuse, as the sheen spreads over the whole hemisphere */
+            let sheen_lobe = material.sheen.as_ref().unwrap();
+            let direct = self.direct_light(hit_info.pos, channels, sampler, |light_dir| {
+                (sheen_lobe.brdf(normal, -dir, light_dir) * normal.dot(light_dir), DIFFUSE_PDF)
+            });
+            color += sheen.mul_element_wise(direct) / p_sheen;
+
+            let new_dir = random_hemisphere_sample(normal, dir_sample);
+            let factor = sheen_lobe.brdf(normal, -dir, new_dir) * normal.dot(new_dir) / DIFFUSE_PDF;
+            color += factor * sheen.mul_element_wise(self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_sheen, Ray::Scattered { pdf: DIFFUSE_PDF }, channels, sampler)) / p_sheen;
+        }
 
+        color
+    }
+
+    /* a random walk through the medium under a subsurface material, after Chiang et al. (2016).
+     * the surface reflects what Fresnel says it does and lets the rest through as if it were
+     * lambertian both ways, and inside the walk goes on by delta tracking until the medium
+     * absorbs it or it gets back out. the walk is a loop rather than recursion as dense media
+     * take hundreds of steps */
+    fn subsurface(&self, hit_info: HitInfo, material: &Material, dir: Vector3<f32>, weight: f32, channels: Channels, sampler: &mut dyn Sampler) -> Color {
+        let medium = material.medium.as_ref().expect("subsurface material without a medium");
+        let mut hit_info = hit_info;
+        let mut dir = dir;
+        let mut throughput = Vector3::new(1.0, 1.0, 1.0);
+        let mut steps = 0;
+
+        loop {
+            /* at the surface, with normal facing the way the ray came from */
             let normal = match hit_info.side {
-                Side::Back => -hit_info.normal,
-                Side::Front => hit_info.normal,
+                Side::Back => -material.shading_normal(&hit_info),
+                Side::Front => material.shading_normal(&hit_info),
             };
-
-            /* Schlick's approximation for Fresnel reflectance */
+            let index = material.index(&hit_info, channels);
             let (n1, n2) = match hit_info.side {
-                Side::Back => (material.refraction_index, 1.0),
-                Side::Front => (1.0, material.refraction_index)
+                Side::Back => (index, 1.0),
+                Side::Front => (1.0, index)
             };
             let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
             let ar = r0 + (1.0 - r0) * (1.0 - normal.dot(-dir)).powi(5);
 
-            let p_diffuse = (diffuse.x + diffuse.y + diffuse.z) / 3.0;
-            let p_refraction = (1.0 - ar) * (refraction.x + refraction.y + refraction.z) / 3.0;
-            let p_reflection = (1.0 + ar) * (reflection.x + reflection.y + reflection.z) / 3.0;
+            let rnd = sampler.next_1d();
+            let dir_sample = sampler.next_2d();
+            if rnd < ar {
+                let new_dir = random_rotation(reflect_ray(-dir, normal), material.reflection_glossiness.value_at(&hit_info), dir_sample);
+                if hit_info.side == Side::Front {
+                    return self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight, Ray::Specular { differential: None }, channels, sampler);
+                }
+                dir = new_dir;
+            } else {
+                /* a lambertian btdf is 1 / pi, against uniform hemisphere sampling's 1 / 2pi */
+                let through = -normal;
+                let new_dir = random_hemisphere_sample(through, dir_sample);
+                let transmission = 2.0 * through.dot(new_dir);
+                if hit_info.side == Side::Back {
+                    let mut color = self.direct_light(hit_info.pos, channels, sampler, |light_dir| (through.dot(light_dir) / consts::PI, DIFFUSE_PDF));
+                    color += transmission * self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight, Ray::Scattered { pdf: DIFFUSE_PDF }, channels, sampler);
+                    return throughput.mul_element_wise(color);
+                }
+                dir = new_dir;
+                throughput *= transmission;
+            }
+
+            /* through the medium to the next point on the surface */
+            let mut pos = hit_info.pos + BIAS * dir;
+            loop {
+                let hit = self.intersect(pos, dir);
+                let segment = MediumSegment {
+                    medium,
+                    grid: None,
+                    pos,
+                    dir,
+                    start: 0.0,
+                    end: hit.as_ref().map_or(f32::INFINITY, |(hit_info, _)| hit_info.z),
+                    channels,
+                };
+                match segment.sample(sampler) {
+                    MediumEvent::Scatter { distance, weight: scatter_weight } => {
+                        steps += 1;
+                        if steps > MAX_SUBSURFACE_STEPS {
+                            return Vector3::zero();
+                        }
+                        throughput = throughput.mul_element_wise(scatter_weight);
+                        pos += distance * dir;
+                        dir = medium.sample_phase(dir, sampler.next_2d());
+                    },
+                    MediumEvent::Absorb => return Vector3::zero(),
+                    MediumEvent::Pass { weight: pass_weight } => {
+                        throughput = throughput.mul_element_wise(pass_weight);
+                        match hit {
+                            Some((next_hit, node)) => {
+                                /* anything else inside the object is shaded as it would be
+                                 * anywhere else */
+                                if next_hit.side == Side::Front || !ptr::eq(self.material(node), material) {
+                                    return throughput.mul_element_wise(self.shade((next_hit, node), dir, weight, Ray::Specular { differential: None }, channels, sampler));
+                                }
+                                hit_info = next_hit;
+                                break;
+                            },
+                            /* out through a hole in the mesh */
+                            None => return throughput.mul_element_wise(self.escape(dir, channels)),
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    /* light scattered back along dir at a point inside a medium. the phase function is sampled
+     * exactly, so the new direction needs no weight of its own */
+    fn scatter(&self, pos: Vector3<f32>, dir: Vector3<f32>, medium: &Medium, weight: f32, channels: Channels, sampler: &mut dyn Sampler) -> Color {
+        /* Russian Roulette */
+        if sampler.next_1d() > weight {
+            return Vector3::zero();
+        }
+
+        let mut color = self.direct_light(pos, channels, sampler, |light_dir| {
+            let phase = medium.phase(dir, light_dir);
+            (phase, phase)
+        });
+
+        let new_dir = medium.sample_phase(dir, sampler.next_2d());
+        let pdf = medium.phase(dir, new_dir);
+        color += self.cast(pos, new_dir, weight * medium.albedo(), Ray::Scattered { pdf }, channels, sampler);
+        color
+    }
+
+    /* light arriving at pos from one light picked at random, weighted by scatter, which gives
+     * how much of the light from a direction is scattered on and the density bounces pick it with */
+    fn direct_light<F: Fn(Vector3<f32>) -> (f32, f32)>(&self, pos: Vector3<f32>, channels: Channels, sampler: &mut dyn Sampler, scatter: F) -> Color {
+        let choice = sampler.next_1d();
+        let sample = sampler.next_2d();
+
+        let num_lights = self.num_lights();
+        if num_lights == 0 {
+            return Vector3::zero();
+        }
+
+        let i = ((choice * num_lights as f32) as usize).min(num_lights - 1);
+        let light_sample = if i < self.emitters.len() {
+            self.emitters[i].sample(pos, sample)
+        } else if i < self.emitters.len() + self.lights.len() {
+            self.lights[i - self.emitters.len()].sample(pos, sample)
+        } else {
+            /* the environment is also reached by scattered bounces, so the two are weighted
+             * against each other with the power heuristic */
+            self.environment_light.as_ref().unwrap().sample(sample).map(|(dir, pdf)| {
+                let light_pdf = pdf / num_lights as f32;
+                let (_, scatter_pdf) = scatter(dir);
+                let mis = light_pdf * light_pdf / (light_pdf * light_pdf + scatter_pdf * scatter_pdf);
+                LightSample {
+                    dir,
+                    distance: f32::INFINITY,
+                    color: mis * self.environment.sample_environment(dir) / pdf,
+                }
+            })
+        };
+
+        match light_sample {
+            Some(light_sample) => {
+                let (factor, _) = scatter(light_sample.dir);
+                if factor <= 0.0 {
+                    return Vector3::zero();
+                }
+                let transmittance = self.transmittance(pos + BIAS * light_sample.dir, light_sample.dir, light_sample.distance - 2.0 * BIAS, channels, sampler);
+                factor * num_lights as f32 * transmittance.mul_element_wise(channels.color(light_sample.color))
+            },
+            None => Vector3::zero(),
+        }
+    }
 
-            let p_range = p_diffuse + p_reflection + p_refraction;
+    fn num_lights(&self) -> usize {
+        self.emitters.len() + self.lights.len() + self.environment_light.iter().count()
+    }
 
-            let mut color = material.emission;
+    /* how much light gets from pos to distance along dir, through media and the boundaries
+     * around them. anything else in the way blocks it */
+    fn transmittance(&self, pos: Vector3<f32>, dir: Vector3<f32>, distance: f32, channels: Channels, sampler: &mut dyn Sampler) -> Color {
+        let mut pos = pos;
+        let mut distance = distance;
+        let mut transmittance = Vector3::new(1.0, 1.0, 1.0);
+        loop {
+            let hit = self.intersect(pos, dir);
+            if let Some(mut segment) = self.segment_medium(pos, dir, hit.as_ref(), channels) {
+                segment.end = segment.end.min(distance);
+                if segment.end > segment.start {
+                    transmittance = transmittance.mul_element_wise(segment.transmittance(sampler));
+                }
+            }
 
-            /* Russian Roulette */
-            if p_range == 0.0 || rand::random::<f32>() > weight {
-                return color;
+            match hit {
+                Some((hit_info, node)) if hit_info.z < distance => {
+                    if !self.material(node).is_boundary() {
+                        return Vector3::zero();
+                    }
+                    pos = hit_info.pos + BIAS * dir;
+                    distance -= hit_info.z + BIAS;
+                },
+                _ => return transmittance,
             }
+        }
+    }
 
-            let p_diffuse = p_diffuse / p_range;
-            let p_reflection = p_reflection / p_range;
-            let p_refraction = p_refraction / p_range;
-
-            let rnd = rand::random::<f32>();
-            if rnd < p_diffuse {
-                let new_dir = random_hemisphere_sample(normal);
-                color += normal.dot(new_dir) * diffuse.mul_element_wise(self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_diffuse)
-                    .unwrap_or_else(|| self.environment.sample_environment(new_dir))) / p_diffuse;
-            } else if rnd < p_diffuse + p_reflection {
-                let new_dir = random_rotation(reflect_ray(-dir, normal), material.reflection_glossiness);
-                color += normal.dot(new_dir) * reflection.mul_element_wise(self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_reflection)
-                    .unwrap_or_else(|| self.environment.sample_environment(new_dir))) / p_reflection;
-            } else if rnd < p_diffuse + p_reflection + p_refraction {
-                let new_dir = random_rotation(refract_ray(-dir, normal, n1, n2).unwrap_or_else(|| reflect_ray(-dir, normal)), material.refraction_glossiness);
-                color += normal.dot(new_dir) * refraction.mul_element_wise(self.cast(hit_info.pos + BIAS * new_dir, new_dir, weight * p_refraction)
-                    .unwrap_or_else(|| self.environment.sample_environment(new_dir))) / p_refraction;
+    /* the stretch of medium a ray from pos along dir goes through before it hits anything: the
+     * inside of the closed object it's leaving, or the fog around everything. media don't nest,
+     * so an object inside another's medium is surrounded by the fog */
+    fn segment_medium<'a>(&'a self, pos: Vector3<f32>, dir: Vector3<f32>, hit: Option<&(HitInfo, &'a Node)>, channels: Channels) -> Option<MediumSegment<'a>> {
+        let end = hit.map_or(f32::INFINITY, |(hit_info, _)| hit_info.z);
+        if let Some((hit_info, node)) = hit {
+            let geometry = &node.object.as_ref().unwrap().geometry;
+            let (closed, grid) = match *geometry {
+                Geometry::Sphere | Geometry::Mesh(_) => (true, None),
+                Geometry::Volume(ref grid) => (true, Some(&**grid)),
+                Geometry::Plane | Geometry::Disk => (false, None),
+            };
+            if closed && hit_info.side == Side::Back {
+                return self.material(node).medium.as_ref().map(|medium| MediumSegment {
+                    medium,
+                    grid,
+                    pos,
+                    dir,
+                    start: 0.0,
+                    end,
+                    channels,
+                });
             }
+        }
 
-            color
-        });
+        self.medium.as_ref().and_then(|medium| {
+            self.bounds.range(pos, dir).and_then(|(t_in, t_out)| {
+                let (start, end) = (t_in.max(0.0), t_out.min(end));
+                if start < end {
+                    Some(MediumSegment { medium, grid: None, pos, dir, start, end, channels })
+                } else {
+                    None
+                }
+            })
+        })
+    }
 
-        result
+    fn material(&self, node: &Node) -> &Material {
+        self.materials.get(&node.object.as_ref().unwrap().material[..])
+            .expect("material does not exist for object")
     }
 
-    pub fn intersect(&self, pos: Vector3<f32>, dir: Vector3<f32>) -> Option<(HitInfo, &Node)> {
-        let mut nearest: Option<(HitInfo, &Node)> = None;
+    /* light from a scattered bounce that leaves the scene, the other half of direct_light's
+     * weighting of the environment */
+    fn escape_scattered(&self, dir: Vector3<f32>, pdf: f32, channels: Channels) -> Color {
+        let color = match self.environment_light {
+            Some(ref environment_light) => {
+                let light_pdf = environment_light.pdf(dir) / self.num_lights() as f32;
+                let mis = pdf * pdf / (light_pdf * light_pdf + pdf * pdf);
+                mis * self.environment.sample_environment(dir) + self.ambient()
+            },
+            None => self.environment.sample_environment(dir) + self.ambient(),
+        };
+        channels.color(color)
+    }
 
-        for node in self.nodes.iter() {
-            if let Some((hit_info, node)) = node.intersect(pos, dir) {
-                if let Some((nearest_hit_info, nearest_node)) = nearest {
-                    nearest = if hit_info.z < nearest_hit_info.z {
-                        Some((hit_info, node))
-                    } else {
-                        Some((nearest_hit_info, nearest_node))
-                    };
-                } else {
-                    nearest = Some((hit_info, node));
+    /* light from rays that leave the scene. sun disks are only seen here, scattered bounces
+     * leave them to direct_light */
+    fn escape(&self, dir: Vector3<f32>, channels: Channels) -> Color {
+        let mut color = self.environment.sample_environment(dir) + self.ambient();
+        for light in &self.lights {
+            if let LightType::Directional { direction, cos_radius } = light.light_type {
+                if cos_radius < 1.0 && -direction.dot(dir) >= cos_radius {
+                    let solid_angle = 2.0 * consts::PI * (1.0 - cos_radius);
+                    color += light.intensity * light.color / solid_angle;
                 }
             }
         }
+        channels.color(color)
+    }
 
-        nearest
+    fn ambient(&self) -> Color {
+        let mut color = Vector3::zero();
+        for light in &self.lights {
+            if let LightType::Ambient = light.light_type {
+                color += light.intensity * light.color;
+            }
+        }
+        color
+    }
+
+    /* the nearest surface along the ray, going through where opacity maps cut surfaces out */
+    pub fn intersect(&self, pos: Vector3<f32>, dir: Vector3<f32>) -> Option<(HitInfo, &Node)> {
+        let mut pos = pos;
+        let mut travelled = 0.0;
+        loop {
+            let mut nearest: Option<(HitInfo, &Node)> = None;
+
+            for node in self.nodes.iter() {
+                if let Some((hit_info, node)) = node.intersect(pos, dir) {
+                    if let Some((nearest_hit_info, nearest_node)) = nearest {
+                        nearest = if hit_info.z < nearest_hit_info.z {
+                            Some((hit_info, node))
+                        } else {
+                            Some((nearest_hit_info, nearest_node))
+                        };
+                    } else {
+                        nearest = Some((hit_info, node));
+                    }
+                }
+            }
+
+            match nearest {
+                Some((ref hit_info, node)) if self.material(node).is_cut_out(hit_info.uv) => {
+                    travelled += hit_info.z + BIAS;
+                    pos = hit_info.pos + BIAS * dir;
+                },
+                Some((mut hit_info, node)) => {
+                    hit_info.z += travelled;
+                    return Some((hit_info, node));
+                },
+                None => return None,
+            }
+        }
     }
 }
 
@@ -224,6 +684,129 @@ fn refract_ray(vec: Vector3<f32>, normal: Vector3<f32>, n1: f32, n2: f32) -> Opt
     }
 }
 
+impl Ray {
+    fn differential(self) -> Option<Differential> {
+        match self {
+            Ray::Camera { differential, .. } | Ray::Specular { differential } => differential,
+            Ray::Scattered { .. } => None,
+        }
+    }
+
+    fn with_differential(self, differential: Option<Differential>) -> Ray {
+        match self {
+            Ray::Camera { x, y, .. } => Ray::Camera { x, y, differential },
+            Ray::Specular { .. } => Ray::Specular { differential },
+            Ray::Scattered { .. } => self,
+        }
+    }
+}
+
+impl Differential {
+    /* at the hit t along dir, on a surface with the given normal */
+    fn transfer(self, dir: Vector3<f32>, t: f32, normal: Vector3<f32>) -> Differential {
+        let cos = dir.dot(normal);
+        let transfer = |dp: Vector3<f32>, dd: Vector3<f32>| {
+            let dp = dp + t * dd;
+            if cos.abs() < EPSILON { dp } else { dp - (dp.dot(normal) / cos) * dir }
+        };
+        Differential { dpdx: transfer(self.dpdx, self.dddx), dpdy: transfer(self.dpdy, self.dddy), ..self }
+    }
+
+    /* after a mirror reflection, as if the surface were flat around the hit */
+    fn reflect(self, normal: Vector3<f32>) -> Differential {
+        let reflect = |dd: Vector3<f32>| dd - 2.0 * dd.dot(normal) * normal;
+        Differential { dddx: reflect(self.dddx), dddy: reflect(self.dddy), ..self }
+    }
+
+    /* after refracting from dir into refracted, with eta the index on dir's side over the one on
+     * the other and normal facing back along dir, again as if the surface were flat */
+    fn refract(self, dir: Vector3<f32>, refracted: Vector3<f32>, normal: Vector3<f32>, eta: f32) -> Differential {
+        let refract = |dd: Vector3<f32>| {
+            let dmu = (eta - eta * eta * (-dir).dot(normal) / refracted.dot(normal).abs()) * (-dd).dot(normal);
+            eta * dd + dmu * normal
+        };
+        Differential { dddx: refract(self.dddx), dddy: refract(self.dddy), ..self }
+    }
+
+    /* the larger of how far u and v move across a pixel in x and in y, given how far the surface
+     * moves for a step in u (tangent) and in v (bitangent) */
+    fn footprint(self, tangent: Vector3<f32>, bitangent: Vector3<f32>) -> f32 {
+        let (a, b, c) = (tangent.dot(tangent), tangent.dot(bitangent), bitangent.dot(bitangent));
+        let det = a * c - b * b;
+        if det.abs() < EPSILON {
+            return 0.0;
+        }
+        /* the least squares solution of dp = du tangent + dv bitangent */
+        let uv_width = |dp: Vector3<f32>| {
+            let (along_u, along_v) = (dp.dot(tangent), dp.dot(bitangent));
+            let (du, dv) = ((c * along_u - b * along_v) / det, (a * along_v - b * along_u) / det);
+            (du * du + dv * dv).sqrt()
+        };
+        uv_width(self.dpdx).max(uv_width(self.dpdy))
+    }
+}
+
+impl Material {
+    /* a surface that doesn't scatter light at all and only marks where a medium starts */
+    pub fn is_boundary(&self) -> bool {
+        let black = Vector3::zero();
+        self.medium.is_some() && !self.subsurface && self.diffuse.color == black && self.reflection.color == black
+            && self.refraction.color == black && self.emission.color == black
+    }
+
+    pub fn is_cut_out(&self, uv: Vector3<f32>) -> bool {
+        self.opacity.as_ref().is_some_and(|opacity| opacity.opacity(uv) < 0.5)
+    }
+
+    /* the index of refraction at a hit, for the hero wavelength */
+    pub fn index(&self, hit_info: &HitInfo, channels: Channels) -> f32 {
+        self.refraction_index.at(channels.hero()) * self.refraction_index_scale.value_at(hit_info)
+    }
+
+    /* the normal tilted by the normal map and the bump map, facing the same side as the
+     * geometric one */
+    pub fn shading_normal(&self, hit_info: &HitInfo) -> Vector3<f32> {
+        if self.normal_map.is_none() && self.bump.is_none() {
+            return hit_info.normal;
+        }
+
+        let normal = hit_info.normal;
+        let tangent = hit_info.tangent - normal.dot(hit_info.tangent) * normal;
+        let (tangent, bitangent) = if tangent.magnitude2() > 1e-12 {
+            let tangent = tangent.normalize();
+            let bitangent = normal.cross(tangent);
+            if bitangent.dot(hit_info.bitangent) < 0.0 { (tangent, -bitangent) } else { (tangent, bitangent) }
+        } else {
+            orthonormal_basis(normal)
+        };
+
+        let mut shading_normal = normal;
+        if let Some(ref normal_map) = self.normal_map {
+            let local = 2.0 * normal_map.sample_at(hit_info) - Vector3::new(1.0, 1.0, 1.0);
+            shading_normal = local.x * tangent + local.y * bitangent + local.z * normal;
+        }
+        if let Some(ref bump) = self.bump {
+            /* the slopes of the height field by finite differences a texel or a pixel apart,
+             * turned from per unit of uv into per unit of distance along the surface */
+            let step = bump.data.texel_size().max(hit_info.footprint);
+            let height = |du: f32, dv: f32| {
+                let color = bump.sample_filtered(hit_info.uv + Vector3::new(du, dv, 0.0), hit_info.footprint);
+                (color.x + color.y + color.z) / 3.0
+            };
+            let h = height(0.0, 0.0);
+            let slope_u = (height(step, 0.0) - h) / step / hit_info.tangent.magnitude().max(1e-6);
+            let slope_v = (height(0.0, step) - h) / step / hit_info.bitangent.magnitude().max(1e-6);
+            shading_normal = shading_normal - slope_u * tangent - slope_v * bitangent;
+        }
+
+        if shading_normal.magnitude2() > 1e-12 && shading_normal.dot(normal) > 0.0 {
+            shading_normal.normalize()
+        } else {
+            normal
+        }
+    }
+}
+
 impl Node {
     fn ray_to_local_space(&self, pos: Vector3<f32>, dir: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
         let local_pos = self.to_local_space(pos);
@@ -266,7 +849,10 @@ impl Node {
                 pos: self.from_local_space(hit_info.pos),
                 uv: hit_info.uv,
                 normal: self.transform.normal_from_local_space(hit_info.normal),
+                tangent: self.transform.transform * hit_info.tangent,
+                bitangent: self.transform.transform * hit_info.bitangent,
                 side: hit_info.side,
+                footprint: hit_info.footprint,
             }, node)
         });
 
@@ -295,13 +881,67 @@ impl Transform {
     }
 }
 
+/* where directions land on a lat-long environment texture */
+pub fn environment_uv(dir: Vector3<f32>) -> (f32, f32) {
+    (0.5 + (dir.y).atan2(dir.x) / (2.0 * consts::PI), 0.5 - (-dir.z).asin() / consts::PI)
+}
+
+pub fn environment_dir(u: f32, v: f32) -> Vector3<f32> {
+    let phi = (u - 0.5) * 2.0 * consts::PI;
+    let z = ((v - 0.5) * consts::PI).sin();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
 impl Texture {
-    fn sample(&self, point: Vector3<f32>) -> Color {
+    pub fn sample(&self, point: Vector3<f32>) -> Color {
         self.color.mul_element_wise(self.data.sample(self.to_local_space(point)))
     }
 
-    fn sample_environment(&self, dir: Vector3<f32>) -> Color {
-        self.sample(Vector3::new(0.5 + (dir.y).atan2(dir.x) / (2.0 * consts::PI), 0.5 - (-dir.z).asin() / consts::PI, 0.0))
+    /* the average over a square width wide around point, both in uv */
+    pub fn sample_filtered(&self, point: Vector3<f32>, width: f32) -> Color {
+        if width <= 0.0 {
+            return self.sample(point);
+        }
+        let local = self.to_local_space(point);
+        let du = self.to_local_space(point + Vector3::new(width, 0.0, 0.0)) - local;
+        let dv = self.to_local_space(point + Vector3::new(0.0, width, 0.0)) - local;
+        self.color.mul_element_wise(self.data.sample_filtered(local, du.magnitude().max(dv.magnitude())))
+    }
+
+    /* the texture over what a pixel covers of a surface */
+    pub fn sample_at(&self, hit_info: &HitInfo) -> Color {
+        self.sample_filtered(hit_info.uv, hit_info.footprint)
+    }
+
+    /* a single number, like a glossiness, given by a texture */
+    pub fn value_at(&self, hit_info: &HitInfo) -> f32 {
+        let color = self.sample_at(hit_info);
+        (color.x + color.y + color.z) / 3.0
+    }
+
+    fn sample_value(&self, point: Vector3<f32>) -> f32 {
+        let color = self.sample(point);
+        (color.x + color.y + color.z) / 3.0
+    }
+
+    /* the alpha of an image that has it, or the texture's value otherwise */
+    pub fn opacity(&self, point: Vector3<f32>) -> f32 {
+        let value = (self.color.x + self.color.y + self.color.z) / 3.0;
+        match self.data.alpha(self.to_local_space(point)) {
+            Some(alpha) => value * alpha,
+            None => self.sample_value(point),
+        }
+    }
+
+    /* whether the texture is the same everywhere, its color */
+    pub fn is_constant(&self) -> bool {
+        matches!(self.data, TextureData::Blank)
+    }
+
+    pub fn sample_environment(&self, dir: Vector3<f32>) -> Color {
+        let (u, v) = environment_uv(dir);
+        self.sample(Vector3::new(u, v, 0.0))
     }
 
     fn to_local_space(&self, vec: Vector3<f32>) -> Vector3<f32> {
@@ -310,40 +950,24 @@ impl Texture {
 }
 
 impl TextureData {
+    /* how far apart in uv neighbouring texels are, or a small step for textures without any */
+    fn texel_size(&self) -> f32 {
+        match *self {
+            TextureData::Image { width, height, .. } | TextureData::HdrImage { width, height, .. } if width > 0 && height > 0 => {
+                1.0 / width.max(height) as f32
+            },
+            _ => 1e-3,
+        }
+    }
+
     fn sample(&self, point: Vector3<f32>) -> Color {
         match *self {
             TextureData::Blank => Vector3::new(1.0, 1.0, 1.0),
-            TextureData::Image { ref pixels, width, height } => {
-                if width + height == 0 {
-                    return Vector3::new(0.0, 0.0, 0.0);
-                }
-
-                let clamped = unit_clamp(point);
-
-                let x = clamped.x * width as f32;
-                let y = clamped.y * height as f32;
-                let mut x1 = x as usize;
-                let mut y1 = y as usize;
-                let xt = x - x1 as f32;
-                let yt = y - y1 as f32;
-
-                x1 = x1 % width;
-                // if x1 < 0 { x1 += width; }
-                y1 = y1 % height;
-                // if y1 < 0 { y1 += height; }
-                let x2 = (x1 + 1) % width;
-                let y2 = (y1 + 1) % height;
-
-                let i00 = 3 * (y1 * width + x1);
-                let i10 = 3 * (y1 * width + x2);
-                let i01 = 3 * (y2 * width + x1);
-                let i11 = 3 * (y2 * width + x2);
-                let p00 = u8_array_as_color(&pixels[i00..i00+3]);
-                let p10 = u8_array_as_color(&pixels[i10..i10+3]);
-                let p01 = u8_array_as_color(&pixels[i01..i01+3]);
-                let p11 = u8_array_as_color(&pixels[i11..i11+3]);
-
-                (1.0-xt)*(1.0-yt) as f32 * p00 + xt*(1.0-yt) as f32 * p10 + (1.0-xt)*yt as f32 * p01 + xt*yt as f32 * p11
+            TextureData::Image { ref pixels, width, height, .. } => {
+                bilinear(point, width, height, |i| u16_array_as_color(&pixels[4 * i..4 * i + 3]))
+            },
+            TextureData::HdrImage { ref pixels, width, height, .. } => {
+                bilinear(point, width, height, |i| Vector3::new(pixels[3 * i], pixels[3 * i + 1], pixels[3 * i + 2]))
             },
             TextureData::Checkerboard { color1, color2 } => {
                 let clamped = unit_clamp(point);
@@ -352,9 +976,129 @@ impl TextureData {
                 } else {
                     color2
                 }
+            },
+            TextureData::Sky(ref sky) => sky.radiance(environment_dir(point.x, point.y)),
+        }
+    }
+
+    /* the average over a square width wide around point: trilinear between the two levels of
+     * the MIP map whose texels are nearest in size for images, and worked out exactly for the
+     * checkerboard */
+    fn sample_filtered(&self, point: Vector3<f32>, width: f32) -> Color {
+        match *self {
+            TextureData::Image { width: image_width, height: image_height, ref mipmap, .. } |
+            TextureData::HdrImage { width: image_width, height: image_height, ref mipmap, .. } => {
+                let level = (width * image_width.max(image_height) as f32).log2();
+                if level.is_nan() || level <= 0.0 || mipmap.is_empty() {
+                    return self.sample(point);
+                }
+                let level = level.min(mipmap.len() as f32);
+                let lower = level.floor() as usize;
+                let t = level - lower as f32;
+                let sample_level = |i: usize| match i {
+                    0 => self.sample(point),
+                    _ => {
+                        let mip_level = &mipmap[i.min(mipmap.len()) - 1];
+                        bilinear(point, mip_level.width, mip_level.height, |j| mip_level.pixels[j])
+                    },
+                };
+                if t > 0.0 {
+                    (1.0 - t) * sample_level(lower) + t * sample_level(lower + 1)
+                } else {
+                    sample_level(lower)
+                }
+            },
+            TextureData::Checkerboard { color1, color2 } => {
+                /* in squares, which are half a unit wide, and integrated along x and y on their
+                 * own as in pbrt's closed form */
+                let (s, t, half_width) = (2.0 * point.x, 2.0 * point.y, width);
+                if half_width >= 1.0 {
+                    return 0.5 * (color1 + color2);
+                }
+                if (s - half_width).floor() == (s + half_width).floor() && (t - half_width).floor() == (t + half_width).floor() {
+                    return self.sample(point);
+                }
+                /* how much of 0 to x is covered by odd squares */
+                let odd = |x: f32| (x / 2.0).floor() + 2.0 * (x / 2.0 - (x / 2.0).floor() - 0.5).max(0.0);
+                let s_odd = (odd(s + half_width) - odd(s - half_width)) / (2.0 * half_width);
+                let t_odd = (odd(t + half_width) - odd(t - half_width)) / (2.0 * half_width);
+                /* color2 is where one of the two is odd but not both */
+                let area2 = s_odd + t_odd - 2.0 * s_odd * t_odd;
+                (1.0 - area2) * color1 + area2 * color2
+            },
+            _ => self.sample(point),
+        }
+    }
+
+    /* the alpha of an image that has an alpha channel */
+    fn alpha(&self, point: Vector3<f32>) -> Option<f32> {
+        match *self {
+            TextureData::Image { ref pixels, width, height, alpha: true, .. } => {
+                let alpha = |i: usize| {
+                    let alpha = pixels[4 * i + 3] as f32 / 65535.0;
+                    Vector3::new(alpha, alpha, alpha)
+                };
+                Some(bilinear(point, width, height, alpha).x)
+            },
+            _ => None,
+        }
+    }
+}
+
+/* the levels below an image width by height, texel giving the color at an index. odd sizes
+ * round up, with the last row or column counted twice */
+pub fn build_mipmap<F: Fn(usize) -> Color>(width: usize, height: usize, texel: F) -> Vec<MipLevel> {
+    let mut mipmap: Vec<MipLevel> = Vec::new();
+    let (mut width, mut height) = (width, height);
+    while width > 1 || height > 1 {
+        let (next_width, next_height) = (width.div_ceil(2), height.div_ceil(2));
+        let mut pixels = Vec::with_capacity(next_width * next_height);
+        for y in 0..next_height {
+            for x in 0..next_width {
+                let mut sum = Vector3::new(0.0, 0.0, 0.0);
+                for &(dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let i = (2 * y + dy).min(height - 1) * width + (2 * x + dx).min(width - 1);
+                    sum += match mipmap.last() {
+                        Some(level) => level.pixels[i],
+                        None => texel(i),
+                    };
+                }
+                pixels.push(sum / 4.0);
             }
         }
+        mipmap.push(MipLevel { width: next_width, height: next_height, pixels });
+        width = next_width;
+        height = next_height;
     }
+    mipmap
+}
+
+/* interpolates between the four texels around point, texel giving the color at an index */
+fn bilinear<F: Fn(usize) -> Color>(point: Vector3<f32>, width: usize, height: usize, texel: F) -> Color {
+    if width + height == 0 {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+
+    let clamped = unit_clamp(point);
+
+    let x = clamped.x * width as f32;
+    let y = clamped.y * height as f32;
+    let mut x1 = x as usize;
+    let mut y1 = y as usize;
+    let xt = x - x1 as f32;
+    let yt = y - y1 as f32;
+
+    x1 %= width;
+    y1 %= height;
+    let x2 = (x1 + 1) % width;
+    let y2 = (y1 + 1) % height;
+
+    let p00 = texel(y1 * width + x1);
+    let p10 = texel(y1 * width + x2);
+    let p01 = texel(y2 * width + x1);
+    let p11 = texel(y2 * width + x2);
+
+    (1.0-xt)*(1.0-yt) * p00 + xt*(1.0-yt) * p10 + (1.0-xt)*yt * p01 + xt*yt * p11
 }
 
 const GAMMA: f32 = 1.0/2.2;
@@ -366,12 +1110,15 @@ pub fn color_as_u8_array(color: Color) -> [u8; 4] {
      255]
 }
 
-pub fn u8_array_as_color(color: &[u8]) -> Color {
-    // let alpha = (color[3] as f32 / 255.0).max(0.0).min(1.0);
+pub fn luminance(color: Color) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+pub fn u16_array_as_color(color: &[u16]) -> Color {
     Vector3::new(
-        /*alpha * */(color[0] as f32 / 255.0).max(0.0).min(1.0),
-        /*alpha * */(color[1] as f32 / 255.0).max(0.0).min(1.0),
-        /*alpha * */(color[2] as f32 / 255.0).max(0.0).min(1.0),
+        color[0] as f32 / 65535.0,
+        color[1] as f32 / 65535.0,
+        color[2] as f32 / 65535.0,
     )
 }
 
@@ -391,7 +1138,7 @@ pub fn unit_clamp(point: Vector3<f32>) -> Vector3<f32> {
     Vector3::new(x, y, z)
 }
 
-fn random_rotation(vec: Vector3<f32>, max_angle: f32) -> Vector3<f32> {
+fn random_rotation(vec: Vector3<f32>, max_angle: f32, sample: (f32, f32)) -> Vector3<f32> {
     let x_abs = vec.x.abs(); let y_abs = vec.y.abs(); let z_abs = vec.z.abs();
     let smallest_axis = if x_abs < y_abs && x_abs < z_abs {
         Vector3::unit_x()
@@ -404,13 +1151,13 @@ fn random_rotation(vec: Vector3<f32>, max_angle: f32) -> Vector3<f32> {
     let v = vec.cross(u).normalize();
 
     let z_min = max_angle.cos();
-    let z = z_min + rand::random::<f32>() * (1.0 - z_min);
-    let theta = rand::random::<f32>() * 2.0 * consts::PI;
+    let z = z_min + sample.0 * (1.0 - z_min);
+    let theta = sample.1 * 2.0 * consts::PI;
     let output = vec * z + z.asin().cos() * (theta.cos() * u + theta.sin() * v);
     output.normalize()
 }
 
-fn random_hemisphere_sample(vec: Vector3<f32>) -> Vector3<f32> {
+fn random_hemisphere_sample(vec: Vector3<f32>, sample: (f32, f32)) -> Vector3<f32> {
     let x_abs = vec.x.abs(); let y_abs = vec.y.abs(); let z_abs = vec.z.abs();
     let smallest_axis = if x_abs < y_abs && x_abs < z_abs {
         Vector3::unit_x()
@@ -422,22 +1169,8 @@ fn random_hemisphere_sample(vec: Vector3<f32>) -> Vector3<f32> {
     let u = vec.cross(smallest_axis).normalize();
     let v = vec.cross(u).normalize();
 
-    let z = rand::random::<f32>();
-    let theta = rand::random::<f32>() * 2.0 * consts::PI;
+    let z = sample.0;
+    let theta = sample.1 * 2.0 * consts::PI;
     let output = vec * z + z.asin().cos() * (theta.cos() * u + theta.sin() * v);
     output.normalize()
 }
-
-pub fn halton(index: i32, base: i32) -> f32 {
-    let mut r = 0.0;
-    let mut f = 1.0;
-
-    let mut i = index;
-    while i > 0 {
-        f /= base as f32;
-        r += f * (i % base) as f32;
-        i /= base;
-    }
-
-    r
-}
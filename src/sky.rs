@@ -0,0 +1,136 @@
+extern crate cgmath;
+
+use scene::*;
+
+use std::f32::consts;
+use self::cgmath::{Vector3, InnerSpace};
+
+/* the Preetham, Shirley and Smits (1999) daylight model: sky radiance from the Perez formula
+ * fitted to turbidity and sun position, in nits, with z up */
+#[derive(Debug, Clone)]
+pub struct Sky {
+    /* pointing towards the sun */
+    pub sun: Vector3<f32>,
+    pub turbidity: f32,
+    /* Perez coefficients and zenith value for luminance and the x and y chromaticities */
+    perez: [[f32; 5]; 3],
+    zenith: [f32; 3],
+    /* radiance of the ground below the horizon */
+    ground: Color,
+}
+
+/* angular radius of the sun seen from the earth */
+pub const SUN_ANGULAR_RADIUS: f32 = 0.2665 * consts::PI / 180.0;
+
+/* illuminance of sunlight above the atmosphere, in lux */
+const SOLAR_ILLUMINANCE: f32 = 128000.0;
+
+impl Sky {
+    pub fn new(sun: Vector3<f32>, turbidity: f32, ground_albedo: f32) -> Sky {
+        let sun = sun.normalize();
+        let t = turbidity;
+        /* the model breaks down once the sun sets */
+        let theta_s = sun.z.clamp(0.0, 1.0).acos().min(consts::PI / 2.0 - 0.001);
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (consts::PI - 2.0 * theta_s);
+        /* in kcd/m^2 */
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (th, th2, th3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_y = t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+        let mut sky = Sky {
+            sun,
+            turbidity,
+            perez,
+            zenith: [1000.0 * zenith_luminance.max(0.0), zenith_x, zenith_y],
+            ground: Vector3::new(0.0, 0.0, 0.0),
+        };
+
+        /* the ground is lambertian, lit by the sky and the sun */
+        let irradiance = sky.irradiance() + sky.sun_illuminance() * sun.z.max(0.0);
+        sky.ground = ground_albedo * irradiance / consts::PI;
+        sky
+    }
+
+    pub fn radiance(&self, dir: Vector3<f32>) -> Color {
+        if dir.z < 0.0 {
+            return self.ground;
+        }
+
+        let cos_theta = dir.z.max(0.001);
+        let cos_gamma = dir.dot(self.sun).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+        let theta_s = self.sun.z.clamp(0.0, 1.0).acos().min(consts::PI / 2.0 - 0.001);
+
+        let mut yxy = [0.0; 3];
+        for ((value, c), zenith) in yxy.iter_mut().zip(&self.perez).zip(&self.zenith) {
+            let perez = |cos_theta: f32, gamma: f32, cos_gamma: f32| {
+                (1.0 + c[0] * (c[1] / cos_theta).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+            };
+            *value = zenith * perez(cos_theta, gamma, cos_gamma) / perez(1.0, theta_s, theta_s.cos());
+        }
+
+        xyy_to_rgb(yxy[1], yxy[2], yxy[0])
+    }
+
+    /* light falling on the ground from the sky alone, in lux per channel */
+    fn irradiance(&self) -> Color {
+        let (rows, columns) = (32, 64);
+        let mut irradiance = Vector3::new(0.0, 0.0, 0.0);
+        for i in 0..rows {
+            let theta = (i as f32 + 0.5) / rows as f32 * consts::PI / 2.0;
+            for j in 0..columns {
+                let phi = (j as f32 + 0.5) / columns as f32 * 2.0 * consts::PI;
+                let dir = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                let solid_angle = theta.sin() * (consts::PI / 2.0 / rows as f32) * (2.0 * consts::PI / columns as f32);
+                irradiance += self.radiance(dir) * theta.cos() * solid_angle;
+            }
+        }
+        irradiance
+    }
+
+    /* illuminance of the sun on a surface facing it, after passing through the atmosphere */
+    pub fn sun_illuminance(&self) -> Color {
+        if self.sun.z <= 0.0 {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+
+        /* Preetham's appendix: rayleigh and aerosol extinction over the relative optical mass */
+        let theta_s = self.sun.z.min(1.0).acos();
+        let mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |wavelength: f32| {
+            let rayleigh = (-0.008735 * wavelength.powf(-4.08) * mass).exp();
+            let aerosol = (-beta * wavelength.powf(-1.3) * mass).exp();
+            rayleigh * aerosol
+        };
+
+        /* wavelengths in micrometers standing in for red, green and blue */
+        SOLAR_ILLUMINANCE * Vector3::new(transmittance(0.68), transmittance(0.55), transmittance(0.44))
+    }
+}
+
+/* CIE xyY to linear sRGB */
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Color {
+    if y <= 0.0 {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    Vector3::new(
+        (3.2406 * cx - 1.5372 * luminance - 0.4986 * cz).max(0.0),
+        (-0.9689 * cx + 1.8758 * luminance + 0.0415 * cz).max(0.0),
+        (0.0557 * cx - 0.2040 * luminance + 1.0570 * cz).max(0.0),
+    )
+}
@@ -0,0 +1,173 @@
+extern crate cgmath;
+
+use scene::*;
+
+use self::cgmath::Vector3;
+
+/* the range of wavelengths spectral samples are picked from, in nanometers */
+const WAVELENGTH_MIN: f32 = 380.0;
+const WAVELENGTH_MAX: f32 = 780.0;
+
+/* the sodium d line, where refractive indices are usually quoted */
+const WAVELENGTH_D: f32 = 587.6;
+
+/* where red, green and blue stand for a wavelength, as in a metal's index of refraction */
+const RGB_WAVELENGTHS: [f32; 3] = [650.0, 550.0, 450.0];
+
+/* what the three channels of a color stand for along a path: red, green and blue, or radiance
+ * at three wavelengths in nanometers. the first wavelength is the hero, which a refraction that
+ * depends on wavelength keeps while the other two drop out */
+#[derive(Clone, Copy)]
+pub enum Channels {
+    Rgb,
+    Spectral(Vector3<f32>),
+    Hero(Vector3<f32>),
+}
+
+/* how much a material refracts, which may change with wavelength in nanometers */
+#[derive(Debug, Clone)]
+pub enum RefractionIndex {
+    Constant(f32),
+    /* n = a + b / l^2, with l in micrometers */
+    Cauchy { a: f32, b: f32 },
+    /* n^2 = 1 + sum of b l^2 / (l^2 - c), with l in micrometers */
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Channels {
+    /* three wavelengths evenly spread over the visible range, starting from a random hero */
+    pub fn sample(sample: f32) -> Channels {
+        let range = WAVELENGTH_MAX - WAVELENGTH_MIN;
+        let wavelength = |offset: f32| WAVELENGTH_MIN + ((sample + offset) % 1.0) * range;
+        Channels::Spectral(Vector3::new(wavelength(0.0), wavelength(1.0 / 3.0), wavelength(2.0 / 3.0)))
+    }
+
+    /* an rgb color as these channels carry it */
+    pub fn color(self, rgb: Color) -> Color {
+        match self {
+            Channels::Rgb => rgb,
+            Channels::Spectral(wavelengths) => Vector3::new(upsample(rgb, wavelengths.x), upsample(rgb, wavelengths.y), upsample(rgb, wavelengths.z)),
+            Channels::Hero(wavelengths) => Vector3::new(upsample(rgb, wavelengths.x), 0.0, 0.0),
+        }
+    }
+
+    /* the wavelength a refractive index is taken at */
+    pub fn hero(self) -> f32 {
+        match self {
+            Channels::Rgb => WAVELENGTH_D,
+            Channels::Spectral(wavelengths) | Channels::Hero(wavelengths) => wavelengths.x,
+        }
+    }
+
+    /* the wavelength each channel stands for */
+    pub fn wavelengths(self) -> Vector3<f32> {
+        match self {
+            Channels::Rgb => Vector3::new(RGB_WAVELENGTHS[0], RGB_WAVELENGTHS[1], RGB_WAVELENGTHS[2]),
+            Channels::Spectral(wavelengths) | Channels::Hero(wavelengths) => wavelengths,
+        }
+    }
+
+    /* a property given at the wavelengths of red, green and blue at each channel's wavelength,
+     * interpolated in between and held beyond */
+    pub fn property(self, rgb: Color) -> Color {
+        match self {
+            Channels::Rgb => rgb,
+            Channels::Spectral(wavelengths) | Channels::Hero(wavelengths) => {
+                let at = |wavelength: f32| {
+                    let [red, green, blue] = RGB_WAVELENGTHS;
+                    if wavelength >= red {
+                        rgb.x
+                    } else if wavelength >= green {
+                        rgb.y + (rgb.x - rgb.y) * (wavelength - green) / (red - green)
+                    } else if wavelength >= blue {
+                        rgb.z + (rgb.y - rgb.z) * (wavelength - blue) / (green - blue)
+                    } else {
+                        rgb.z
+                    }
+                };
+                Vector3::new(at(wavelengths.x), at(wavelengths.y), at(wavelengths.z))
+            },
+        }
+    }
+
+    /* the channels after a refraction that bends every wavelength a different way, and what
+     * the hero's light gets scaled by to make up for the others */
+    pub fn refract(self, index: &RefractionIndex) -> (Channels, Color) {
+        match (self, index) {
+            (Channels::Spectral(wavelengths), &RefractionIndex::Cauchy { .. }) |
+            (Channels::Spectral(wavelengths), &RefractionIndex::Sellmeier { .. }) => {
+                (Channels::Hero(wavelengths), Vector3::new(3.0, 0.0, 0.0))
+            },
+            _ => (self, Vector3::new(1.0, 1.0, 1.0)),
+        }
+    }
+
+    /* radiance carried by these channels as rgb */
+    pub fn to_rgb(self, color: Color) -> Color {
+        match self {
+            Channels::Rgb => color,
+            Channels::Spectral(wavelengths) | Channels::Hero(wavelengths) => {
+                /* each wavelength is an estimate of the integral over the range on its own */
+                let mut xyz = Vector3::new(0.0, 0.0, 0.0);
+                for &(radiance, wavelength) in &[(color.x, wavelengths.x), (color.y, wavelengths.y), (color.z, wavelengths.z)] {
+                    xyz += radiance * color_matching(wavelength);
+                }
+                xyz_to_rgb(xyz * (WAVELENGTH_MAX - WAVELENGTH_MIN) / (3.0 * Y_INTEGRAL))
+            },
+        }
+    }
+}
+
+impl RefractionIndex {
+    pub fn at(&self, wavelength: f32) -> f32 {
+        let l2 = (wavelength / 1000.0) * (wavelength / 1000.0);
+        match *self {
+            RefractionIndex::Constant(index) => index,
+            RefractionIndex::Cauchy { a, b } => a + b / l2,
+            RefractionIndex::Sellmeier { b, c } => {
+                (1.0 + b[0] * l2 / (l2 - c[0]) + b[1] * l2 / (l2 - c[1]) + b[2] * l2 / (l2 - c[2])).sqrt()
+            },
+        }
+    }
+}
+
+/* the CIE 1931 color matching functions, as the piecewise gaussian fit of Wyman, Sloan and
+ * Shirley (2013) */
+fn color_matching(wavelength: f32) -> Vector3<f32> {
+    let g = |mean: f32, below: f32, above: f32| {
+        let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    Vector3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/* the integral of the fitted y, the sum over its lobes of sqrt(pi / 2) times the height times
+ * both widths added up, so that a flat spectrum of 1 has Y 1 */
+const Y_INTEGRAL: f32 = 106.92;
+
+/* CIE XYZ to linear sRGB, with each row scaled so that a flat spectrum comes out white */
+fn xyz_to_rgb(xyz: Vector3<f32>) -> Color {
+    Vector3::new(
+        (3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z) / 1.2048,
+        (-0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z) / 0.9484,
+        (0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z) / 0.9087,
+    )
+}
+
+/* the value at a wavelength of a spectrum with the given rgb color. the spectrum is a mix of
+ * three boxes, blue below 490nm, green up to 590nm and red above, weighted by the inverse of
+ * the colors the boxes have, so a color goes there and back unchanged and white stays flat */
+fn upsample(rgb: Color, wavelength: f32) -> f32 {
+    let weight = if wavelength >= 590.0 {
+        1.0168 * rgb.x - 0.0046 * rgb.y - 0.0086 * rgb.z
+    } else if wavelength >= 490.0 {
+        0.0153 * rgb.x + 0.9444 * rgb.y + 0.0390 * rgb.z
+    } else {
+        0.0239 * rgb.x + 0.0456 * rgb.y + 0.9316 * rgb.z
+    };
+    weight.max(0.0)
+}